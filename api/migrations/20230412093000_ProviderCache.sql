@@ -0,0 +1,5 @@
+create table if not exists ProviderCache (
+    key text primary key,
+    response text,
+    expires datetime
+);
\ No newline at end of file
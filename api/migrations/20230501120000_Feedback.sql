@@ -0,0 +1,8 @@
+create table if not exists Feedback (
+    username text,
+    endpoint text,
+    message text,
+    answer text,
+    rating integer check (rating in (-1, 1)),
+    timestamp datetime default current_timestamp
+);
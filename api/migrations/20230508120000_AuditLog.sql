@@ -0,0 +1,9 @@
+create table if not exists AuditLog (
+    username text,
+    user_id text,
+    guild_id text,
+    channel_id text,
+    endpoint text,
+    reason text,
+    timestamp datetime default current_timestamp
+);
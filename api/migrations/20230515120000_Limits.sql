@@ -0,0 +1,13 @@
+create table if not exists RateLimit (
+    key text primary key,
+    tokens real not null,
+    updated real not null
+);
+
+create table if not exists Quota (
+    key text not null,
+    day text not null,
+    tokens integer not null default 0,
+    cost real not null default 0,
+    primary key (key, day)
+);
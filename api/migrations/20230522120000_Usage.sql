@@ -0,0 +1,14 @@
+create table if not exists Usage (
+    username text,
+    guild_id text,
+    endpoint text,
+    step text,
+    model text,
+    prompt_tokens integer,
+    completion_tokens integer,
+    estimated boolean,
+    cost real,
+    timestamp datetime default current_timestamp
+);
+
+create index if not exists UsageTimestamp on Usage (timestamp);
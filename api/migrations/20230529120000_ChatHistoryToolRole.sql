@@ -0,0 +1,14 @@
+-- SQLite can't change a check constraint in place, so the table is rebuilt to allow `tool`
+create table ChatHistoryNew (
+    username text,
+    message text,
+    role text check (role in ('system', 'assistant', 'user', 'tool')),
+    timestamp datetime default current_timestamp
+);
+
+insert into ChatHistoryNew (username, message, role, timestamp)
+select username, message, role, timestamp from ChatHistory;
+
+drop table ChatHistory;
+
+alter table ChatHistoryNew rename to ChatHistory;
@@ -0,0 +1,4 @@
+-- names change, so usage is keyed on the user id where the frontend sends one
+alter table Usage add column user_id text;
+
+create index if not exists UsageUserId on Usage (user_id);
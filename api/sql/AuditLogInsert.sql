@@ -0,0 +1,2 @@
+insert into AuditLog (username, user_id, guild_id, channel_id, endpoint, reason)
+values ($1, $2, $3, $4, $5, $6)
\ No newline at end of file
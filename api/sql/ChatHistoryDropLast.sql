@@ -0,0 +1,6 @@
+delete from ChatHistory
+where username = $1
+    and rowid >= (
+        select max(rowid) from ChatHistory
+        where username = $1 and role = 'user' and message = $2
+    )
\ No newline at end of file
@@ -0,0 +1,2 @@
+insert into Feedback (username, endpoint, message, answer, rating)
+values ($1, $2, $3, $4, $5)
\ No newline at end of file
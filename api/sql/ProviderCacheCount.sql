@@ -0,0 +1,2 @@
+select count(*) as entries from ProviderCache
+where expires > current_timestamp
\ No newline at end of file
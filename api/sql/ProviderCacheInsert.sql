@@ -0,0 +1,2 @@
+insert or replace into ProviderCache (key, response, expires)
+values ($1, $2, datetime('now', $3))
\ No newline at end of file
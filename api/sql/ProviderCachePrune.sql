@@ -0,0 +1,2 @@
+delete from ProviderCache
+where expires <= current_timestamp
\ No newline at end of file
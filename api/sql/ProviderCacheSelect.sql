@@ -0,0 +1,2 @@
+select response from ProviderCache
+where key = $1 and expires > current_timestamp
\ No newline at end of file
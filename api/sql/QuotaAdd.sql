@@ -0,0 +1,3 @@
+insert into Quota (key, day, tokens, cost)
+values ($1, $2, $3, $4)
+on conflict (key, day) do update set tokens = tokens + excluded.tokens, cost = cost + excluded.cost
\ No newline at end of file
@@ -0,0 +1 @@
+select tokens, cost from Quota where key = $1 and day = $2
\ No newline at end of file
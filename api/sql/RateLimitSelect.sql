@@ -0,0 +1 @@
+select tokens, updated from RateLimit where key = $1
\ No newline at end of file
@@ -0,0 +1,2 @@
+insert or replace into RateLimit (key, tokens, updated)
+values ($1, $2, $3)
\ No newline at end of file
@@ -0,0 +1,2 @@
+insert into Usage (username, user_id, guild_id, endpoint, step, model, prompt_tokens, completion_tokens, estimated, cost)
+values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
\ No newline at end of file
@@ -0,0 +1,16 @@
+-- with max() as the only min/max aggregate, sqlite takes the bare username from the latest call
+select date(timestamp) as day,
+    coalesce(user_id, username) as user_key,
+    username,
+    max(timestamp) as latest,
+    endpoint,
+    count(*) as calls,
+    sum(prompt_tokens) as prompt_tokens,
+    sum(completion_tokens) as completion_tokens,
+    sum(cost) as cost
+from Usage
+where timestamp >= datetime('now', $1)
+    and ($2 is null or coalesce(user_id, username) = $2)
+    and ($3 is null or guild_id = $3)
+group by day, user_key, endpoint
+order by day desc, cost desc
\ No newline at end of file
@@ -0,0 +1,307 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use architectury::prelude::*;
+use axum::http::StatusCode;
+use eyre::{eyre, Context};
+use once_cell::sync::Lazy;
+use openchad_schemas::provider::ProviderAuth;
+use reqwest::{RequestBuilder, Response};
+use serde::Deserialize;
+use tokio::sync::Mutex;
+
+/// Tokens are refreshed this long before they actually expire
+const TOKEN_LEEWAY: Duration = Duration::from_secs(30);
+
+// each token has its own lock, so a slow token endpoint only holds up the providers that use it
+static TOKENS: Lazy<std::sync::Mutex<HashMap<(String, String), TokenSlot>>> =
+    Lazy::new(|| std::sync::Mutex::new(HashMap::new()));
+
+type TokenSlot = Arc<Mutex<Option<CachedToken>>>;
+
+struct CachedToken {
+    access_token: String,
+    expires_at: Option<Instant>,
+}
+
+impl CachedToken {
+    /// Whether the token can still be sent at `now` without cutting it close to its expiry
+    fn fresh_at(&self, now: Instant) -> bool {
+        self.expires_at
+            .is_none_or(|expires_at| now + TOKEN_LEEWAY < expires_at)
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+}
+
+/// Renders every templated field of `auth` with `render`
+pub fn render_auth(
+    auth: &ProviderAuth,
+    render: impl Fn(&str) -> Result<String>,
+) -> Result<ProviderAuth> {
+    Ok(match auth {
+        ProviderAuth::Bearer { token } => ProviderAuth::Bearer {
+            token: render(token)?,
+        },
+        ProviderAuth::Basic { username, password } => ProviderAuth::Basic {
+            username: render(username)?,
+            password: render(password)?,
+        },
+        ProviderAuth::QueryParam { name, value } => ProviderAuth::QueryParam {
+            name: render(name)?,
+            value: render(value)?,
+        },
+        ProviderAuth::Oauth2ClientCredentials {
+            token_url,
+            client_id,
+            client_secret,
+            scope,
+        } => ProviderAuth::Oauth2ClientCredentials {
+            token_url: render(token_url)?,
+            client_id: render(client_id)?,
+            client_secret: render(client_secret)?,
+            scope: scope.as_deref().map(&render).transpose()?,
+        },
+    })
+}
+
+/// Rendered credentials that must never end up in logs, wherever else they're templated in
+pub fn secrets(auth: &ProviderAuth) -> Vec<String> {
+    match auth {
+        ProviderAuth::Bearer { token } => vec![token.clone()],
+        ProviderAuth::Basic { username, password } => vec![username.clone(), password.clone()],
+        ProviderAuth::QueryParam { value, .. } => vec![value.clone()],
+        ProviderAuth::Oauth2ClientCredentials { client_secret, .. } => {
+            vec![client_secret.clone()]
+        }
+    }
+}
+
+pub fn redact<S: AsRef<str>>(source: &str, secrets: &[S]) -> String {
+    secrets
+        .iter()
+        .map(AsRef::as_ref)
+        .filter(|secret| !secret.is_empty())
+        .fold(source.to_string(), |acc, secret| acc.replace(secret, "***"))
+}
+
+/// Sends the request with `auth` applied. OAuth2 tokens that get rejected are refreshed once.
+pub async fn send_authorized(builder: RequestBuilder, auth: &ProviderAuth) -> Result<Response> {
+    let retry = builder.try_clone();
+    let response = authorize(builder, auth, false).await?.send().await?;
+
+    match (auth, retry) {
+        (ProviderAuth::Oauth2ClientCredentials { .. }, Some(retry))
+            if response.status() == StatusCode::UNAUTHORIZED =>
+        {
+            warn!("OAuth2 token was rejected, refreshing");
+            Ok(authorize(retry, auth, true).await?.send().await?)
+        }
+        _ => Ok(response),
+    }
+}
+
+async fn authorize(
+    builder: RequestBuilder,
+    auth: &ProviderAuth,
+    refresh: bool,
+) -> Result<RequestBuilder> {
+    Ok(match auth {
+        ProviderAuth::Bearer { token } => builder.bearer_auth(token),
+        ProviderAuth::Basic { username, password } => builder.basic_auth(username, Some(password)),
+        ProviderAuth::QueryParam { name, value } => builder.query(&[(name, value)]),
+        ProviderAuth::Oauth2ClientCredentials {
+            token_url,
+            client_id,
+            client_secret,
+            scope,
+        } => builder.bearer_auth(
+            client_credentials_token(token_url, client_id, client_secret, scope, refresh).await?,
+        ),
+    })
+}
+
+async fn client_credentials_token(
+    token_url: &str,
+    client_id: &str,
+    client_secret: &str,
+    scope: &Option<String>,
+    refresh: bool,
+) -> Result<String> {
+    let key = (token_url.to_string(), client_id.to_string());
+    let slot = TOKENS.lock().unwrap().entry(key).or_default().clone();
+    let mut cached_token = slot.lock().await;
+
+    if !refresh {
+        if let Some(cached) = cached_token.as_ref() {
+            if cached.fresh_at(Instant::now()) {
+                return Ok(cached.access_token.clone());
+            }
+        }
+    }
+
+    info!("Requesting OAuth2 token from {token_url:?} for client {client_id:?}");
+
+    let mut form = vec![
+        ("grant_type", "client_credentials"),
+        ("client_id", client_id),
+        ("client_secret", client_secret),
+    ];
+
+    if let Some(scope) = scope {
+        form.push(("scope", scope));
+    }
+
+    let response = reqwest::Client::new()
+        .post(token_url)
+        .form(&form)
+        .send()
+        .await
+        .context("Failed to request OAuth2 token")?;
+
+    if !response.status().is_success() {
+        return Err(eyre!(
+            "OAuth2 token endpoint responded with {}",
+            response.status()
+        ));
+    }
+
+    let token: TokenResponse = response
+        .json()
+        .await
+        .context("Invalid OAuth2 token response")?;
+
+    *cached_token = Some(CachedToken {
+        access_token: token.access_token.clone(),
+        expires_at: token
+            .expires_in
+            .map(|secs| Instant::now() + Duration::from_secs(secs)),
+    });
+
+    Ok(token.access_token)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use architectury::prelude::*;
+    use openchad_schemas::provider::ProviderAuth;
+    use tokio::sync::Mutex;
+
+    use super::{
+        client_credentials_token, redact, render_auth, secrets, CachedToken, TOKENS, TOKEN_LEEWAY,
+    };
+
+    #[test]
+    fn redacts_every_secret_kind() -> Result<()> {
+        let env = HashMap::from([
+            ("{{ env.TOKEN }}", "bearer-token"),
+            ("{{ env.USER }}", "basic-user"),
+            ("{{ env.PASSWORD }}", "basic-password"),
+            ("{{ env.KEY }}", "query-key"),
+            ("{{ env.CLIENT_SECRET }}", "client-secret"),
+            ("{{ env.SCOPE }}", ""),
+        ]);
+        let render = |source: &str| Ok(env.get(source).unwrap_or(&source).to_string());
+
+        let auths = [
+            ProviderAuth::Bearer {
+                token: "{{ env.TOKEN }}".into(),
+            },
+            ProviderAuth::Basic {
+                username: "{{ env.USER }}".into(),
+                password: "{{ env.PASSWORD }}".into(),
+            },
+            ProviderAuth::QueryParam {
+                name: "key".into(),
+                value: "{{ env.KEY }}".into(),
+            },
+            ProviderAuth::Oauth2ClientCredentials {
+                token_url: "https://auth.example.com/token".into(),
+                client_id: "openchad".into(),
+                client_secret: "{{ env.CLIENT_SECRET }}".into(),
+                scope: Some("{{ env.SCOPE }}".into()),
+            },
+        ];
+
+        let mut secrets = auths
+            .iter()
+            .map(|auth| Ok(secrets(&render_auth(auth, render)?)))
+            .collect::<Result<Vec<_>>>()?
+            .concat();
+        // an unset env var renders empty and must not mask every gap in the line
+        secrets.push(String::new());
+
+        let line = format!(
+            "<searchWeb> Required prop \"q\" = `volcano {}`",
+            env.values().copied().collect::<Vec<_>>().join(" ")
+        );
+        let redacted = redact(&line, &secrets);
+
+        for secret in env.values().filter(|secret| !secret.is_empty()) {
+            assert!(!redacted.contains(secret), "{secret} leaked: {redacted}");
+        }
+        assert!(redacted.starts_with("<searchWeb> Required prop \"q\" = `volcano "));
+        assert_eq!(redacted.matches("***").count(), 5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn tokens_go_stale_within_the_leeway() {
+        let now = Instant::now();
+        let token = |expires_in: Option<u64>| CachedToken {
+            access_token: "token".into(),
+            expires_at: expires_in.map(|secs| now + Duration::from_secs(secs)),
+        };
+
+        assert!(token(None).fresh_at(now));
+        assert!(token(Some(3600)).fresh_at(now));
+        assert!(!token(Some(3600)).fresh_at(now + Duration::from_secs(3600)));
+        assert!(!token(Some(TOKEN_LEEWAY.as_secs())).fresh_at(now));
+        assert!(!token(Some(TOKEN_LEEWAY.as_secs() + 5)).fresh_at(now + Duration::from_secs(10)));
+    }
+
+    #[tokio::test]
+    async fn reuses_fresh_tokens() -> Result<()> {
+        // nothing listens here, so any token that isn't reused fails to refresh
+        let token_url = "http://127.0.0.1:9/token";
+        let cache = |expires_in: u64| {
+            TOKENS.lock().unwrap().insert(
+                (token_url.into(), "openchad".into()),
+                Arc::new(Mutex::new(Some(CachedToken {
+                    access_token: "cached".into(),
+                    expires_at: Some(Instant::now() + Duration::from_secs(expires_in)),
+                }))),
+            )
+        };
+
+        cache(3600);
+        assert_eq!(
+            client_credentials_token(token_url, "openchad", "secret", &None, false).await?,
+            "cached"
+        );
+        assert!(
+            client_credentials_token(token_url, "openchad", "secret", &None, true)
+                .await
+                .is_err()
+        );
+
+        cache(TOKEN_LEEWAY.as_secs() / 2);
+        assert!(
+            client_credentials_token(token_url, "openchad", "secret", &None, false)
+                .await
+                .is_err()
+        );
+
+        Ok(())
+    }
+}
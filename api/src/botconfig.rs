@@ -2,14 +2,13 @@ macro_rules! append_to_history {
     ($pool: ident, $body: ident, $response: ident) => {
         append_history(
             &$pool.clone(),
-            $body.user.clone(),
-            openchad_schemas::chat::ChatMessage {
-                role: "user".into(),
-                content: $body.message,
-            },
+            $body.history_key().to_owned(),
+            openchad_schemas::chat::ChatMessage::new(
+                openchad_schemas::chat::ChatRole::User,
+                $body.message,
+            ),
         )
-        .await
-        .unwrap();
+        .await?;
     };
 }
 
@@ -17,61 +16,93 @@ macro_rules! merge_request_parts {
     ($t: ident, $rt: ident, $rp: expr, $pd: ident, $ctx: ident) => {
         $rp.iter()
             .filter(|(_, prop_options)| prop_options.redirect == Redirect::$rt)
-            .map(|(prop, prop_options)| (prop.clone(), prop_options.value.clone()))
-            .chain($pd.$t.unwrap_or_default().into_iter().map(|(k, v)| {
-                let mut env = Environment::new();
-                env.add_template("template", &v).unwrap();
-                let rendered = env.get_template("template").unwrap().render(&$ctx).unwrap();
-                (k, rendered)
-            }))
-            .collect::<HashMap<_, _>>()
+            .map(|(prop, prop_options)| Ok((prop.clone(), prop_options.value.clone())))
+            .chain(
+                $pd.$t
+                    .clone()
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|(k, v)| Ok((k, template(&v, &$ctx)?))),
+            )
+            .collect::<Result<HashMap<_, _>>>()
     };
 }
 
 macro_rules! json_nl_stream {
     ($response: ident) => {
-        Ok(axum_streams::StreamBodyAs::json_nl($response.filter_map(
-            |i| async move {
-                if let Ok(i) = i {
-                    Some(i)
-                } else {
-                    None
-                }
+        // nothing's sent after the first error, since the answer can't be trusted past it
+        Ok(axum_streams::StreamBodyAs::json_nl($response.scan(
+            false,
+            |failed, i| {
+                let event = match i {
+                    _ if *failed => None,
+                    Ok(i) => Some(StreamEvent::Chunk(i)),
+                    Err(e) => {
+                        *failed = true;
+                        let error = ApiError::from(e);
+                        warn!("Answer stream ended early: {error}");
+                        Some(StreamEvent::Error {
+                            error: error.body(),
+                        })
+                    }
+                };
+                futures::future::ready(event)
             },
         )))
     };
 }
 
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 use std::env::{self, var};
 use std::ops::{Add, Sub};
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 
 use architectury::coreutils::cat;
 use architectury::prelude::*;
 use async_recursion::async_recursion;
-use axum::http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
 use axum::routing::get;
 use axum::{Extension, Json, Router};
 use axum_streams::StreamBodyAs;
 use chrono::{Duration, FixedOffset, Local, TimeZone};
-use eyre::{eyre, ErrReport};
+use eyre::eyre;
 use eyre::{Context, ContextCompat};
-use futures::{pin_mut, Stream, StreamExt, TryStreamExt};
+use futures::stream::BoxStream;
+use futures::{Stream, StreamExt};
 use minijinja::Environment;
 use once_cell::sync::Lazy;
 use openchad_schemas::botconfig::{
-    BotConfig, BotConfigHeadless, ConfigMacro, ConfigProvider, ConfigResponse, Transform,
+    BotConfig, BotConfigHeadless, ConfigMacro, ConfigProvider, ConfigResponse, ConfigTool,
+    Transform,
+};
+use openchad_schemas::chat::{ChatAttachment, ChatContent, ChatMessage, ChatRole};
+use openchad_schemas::provider::Redirect;
+use openchad_schemas::{
+    AutocompleteBody, AutocompleteResponse, CategorizeBody, CategorizeResponse, ChatBody,
+    StreamEvent,
 };
-use openchad_schemas::chat::ChatMessage;
-use openchad_schemas::provider::{Provider, Redirect};
-use openchad_schemas::{CategorizeBody, CategorizeResponse, ChatBody};
 use serde::Serialize;
-use serde_json::Value;
+use serde_json::{json, Value};
 use sqlx::SqlitePool;
 
-use crate::{chat, get_history, internal_error_string};
+use crate::chat::ToolTurn;
+use crate::error::{self, ApiError};
+use crate::provider::{self, ProviderRequest, ProviderResponse};
+use crate::{auth, cache, limits, registry};
+use crate::{chat, get_history};
+
+const DEFAULT_TOOL_ITERATIONS: usize = 4;
+// Discord drops autocomplete responses after 3 seconds and shows at most 25 choices
+const AUTOCOMPLETE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+const AUTOCOMPLETE_CHOICES: usize = 25;
+
+/// What every task of a request works with, whichever task it was reached from
+#[derive(Clone)]
+struct TaskContext {
+    config: Arc<BotConfig>,
+    config_json: Arc<Value>,
+    history: Vec<ChatMessage>,
+    attachments: Vec<ChatAttachment>,
+}
 
 #[derive(Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
@@ -81,6 +112,7 @@ struct ResponseContext {
     props: HashMap<String, String>,
     transform: Transform,
     args: HashMap<String, String>,
+    attachments: Vec<ChatAttachment>,
 }
 
 #[derive(Serialize, Clone)]
@@ -99,11 +131,20 @@ struct ProviderContext {
     env: HashMap<String, String>,
 }
 
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ProviderBodyContext {
+    #[serde(flatten)]
+    response_context: ProviderContext,
+    body: HashMap<String, String>,
+}
+
 #[derive(Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
 struct ProviderTransformContext {
     #[serde(flatten)]
     response_context: ProviderContext,
+    status: u16,
     response: Value,
 }
 
@@ -143,16 +184,23 @@ static ENV: Lazy<Environment> = Lazy::new(|| {
     env
 });
 
+/// Trims the first character of `matches` off both ends of `source`, or nothing if it's empty
 fn trim(source: &str, matches: &str) -> String {
-    source
-        .trim_matches(matches.chars().next().unwrap())
-        .to_string()
+    match matches.chars().next() {
+        Some(c) => source.trim_matches(c).to_string(),
+        None => source.to_string(),
+    }
 }
 
 fn template<T: Serialize, S: AsRef<str>>(source: S, context: &T) -> Result<String> {
     let mut env = ENV.clone();
-    env.add_template("template", source.as_ref())?;
-    Ok(env.get_template("template")?.render(context).unwrap())
+    env.add_template("template", source.as_ref())
+        .map_err(|e| ApiError::Template(e.to_string()))?;
+
+    Ok(env
+        .get_template("template")?
+        .render(context)
+        .map_err(|e| ApiError::Template(e.to_string()))?)
 }
 
 fn template_multiline<T: Serialize>(source: &Vec<String>, context: &T) -> Result<String> {
@@ -167,22 +215,88 @@ async fn append_history(
     pool: &SqlitePool,
     username: String,
     message: ChatMessage,
-) -> Result<(), (StatusCode, String)> {
+) -> Result<(), ApiError> {
     sqlx::query(
         r#"insert into ChatHistory (username, message, role)
     values ($1, $2, $3)"#,
     )
     .bind(username)
-    .bind(message.content)
-    .bind(message.role)
+    .bind(message.content.text())
+    .bind(message.role.as_str())
     .execute(pool)
     .await
-    .context("Failed to append history")
-    .map_err(internal_error_string)?;
+    .context("Failed to append history")?;
+
+    Ok(())
+}
+
+/// Drops `message` from the history along with everything after it, so a regenerated answer
+/// replaces the exchange instead of following it
+async fn forget_last_exchange(
+    pool: &SqlitePool,
+    username: &str,
+    message: &str,
+) -> Result<(), ApiError> {
+    sqlx::query(include_str!("../sql/ChatHistoryDropLast.sql"))
+        .bind(username)
+        .bind(message)
+        .execute(pool)
+        .await
+        .context("Failed to drop the last exchange from the history")?;
 
     Ok(())
 }
 
+/// Suggestions from an autocomplete task, one per line of its `choices` transform. Provider caching
+/// (`cacheTtl`) applies as usual, which helps since the same prefixes come up a lot
+async fn autocomplete(
+    body: AutocompleteBody,
+    config: Arc<BotConfig>,
+    config_json: Arc<Value>,
+) -> Result<Vec<String>> {
+    if !body.task.starts_with("providers.") {
+        return Err(
+            ApiError::BadConfig(format!("`{}` isn't a `providers.*` task", body.task)).into(),
+        );
+    }
+    if body.input.trim().is_empty() {
+        return Ok(vec![]);
+    }
+
+    let task_context = TaskContext {
+        config,
+        config_json,
+        history: vec![],
+        attachments: vec![],
+    };
+    let task = resolve_task(
+        body.task.clone(),
+        &task_context,
+        Transform::new(),
+        body.input.clone(),
+        HashMap::from([("input".into(), body.input)]),
+    );
+
+    let Ok(result) = tokio::time::timeout(AUTOCOMPLETE_TIMEOUT, task).await else {
+        warn!("<{}> Autocomplete timed out", body.task);
+        return Ok(vec![]);
+    };
+    let (_, transform) = result?;
+
+    Ok(transform
+        .get("choices")
+        .map(|choices| {
+            choices
+                .lines()
+                .map(str::trim)
+                .filter(|choice| !choice.is_empty())
+                .take(AUTOCOMPLETE_CHOICES)
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
 fn datetime() -> String {
     let est = FixedOffset::west_opt(180).unwrap();
     let dt = est
@@ -193,73 +307,175 @@ fn datetime() -> String {
     dt.format("%Y-%m-%d %I:%M %p").to_string()
 }
 
-pub fn create_routes(
-    router: Router,
-    config: &'static Arc<BotConfig>,
-    config_json: Arc<Value>,
-) -> Result<Router> {
-    let gen_config = config.clone();
-    let categorize_config: BotConfigHeadless = config.clone().into();
+/// Replied-to messages go after the stored history, since they're what the user is talking about
+fn with_context(history: Vec<ChatMessage>, context: &Option<Vec<ChatMessage>>) -> Vec<ChatMessage> {
+    [history, context.clone().unwrap_or_default()].concat()
+}
 
-    let help_prompt = template_multiline(&gen_config.help_prompt, &categorize_config)?;
-    let categorize_prompt = template_multiline(&gen_config.categorize_prompt, &categorize_config)?;
+/// A config along with what's derived from it when it's loaded
+pub struct LoadedConfig {
+    pub config: Arc<BotConfig>,
+    pub json: Arc<Value>, // Tasks are looked up in this
+    help_prompt: String,
+    categorize_prompt: String,
+}
 
-    Ok(config
-        .endpoints
-        .iter()
-        .fold(Ok(router), |router: Result<Router>, (url, endpoint)| {
-            let config = config.clone();
-            let config_json = config_json.clone();
+// swapped out by `POST /config`, so every request reads it once and sticks with what it got
+static CONFIG: RwLock<Option<Arc<LoadedConfig>>> = RwLock::new(None);
 
+impl LoadedConfig {
+    /// Checks that every endpoint runs a task that can be streamed, and renders the prompts
+    pub fn new(config: BotConfig) -> Result<Self> {
+        let json = serde_json::to_value(&config)?;
+
+        for (url, endpoint) in &config.endpoints {
             let v = endpoint.task.split('.').collect::<Vec<_>>();
 
-            if !config_json[v[0]][v[1]].is_object()
+            if v.len() != 2
+                || !json[v[0]][v[1]].is_object()
                 || !(endpoint.task.starts_with("responses.")
-                    || endpoint.task.starts_with("macros."))
+                    || endpoint.task.starts_with("macros.")
+                    || endpoint.task.starts_with("tools."))
             {
-                return Err::<Router, ErrReport>(eyre!(
+                return Err(eyre!(
                     "Invalid task id `{}` for route {}",
                     endpoint.task,
                     url,
                 ));
             }
-            Ok(router?.route(
-                &url,
+        }
+
+        let config = Arc::new(config);
+        let headless: BotConfigHeadless = config.clone().into();
+
+        Ok(Self {
+            help_prompt: template_multiline(&config.help_prompt, &headless)?,
+            categorize_prompt: template_multiline(&config.categorize_prompt, &headless)?,
+            config,
+            json: Arc::new(json),
+        })
+    }
+}
+
+pub fn current_config() -> Arc<LoadedConfig> {
+    CONFIG
+        .read()
+        .unwrap()
+        .clone()
+        .expect("The config is installed at startup")
+}
+
+pub fn install_config(config: LoadedConfig) {
+    *CONFIG.write().unwrap() = Some(Arc::new(config));
+}
+
+/// Routes for `config`'s endpoints, which are looked up in the current config on every request.
+/// Endpoints can't be added or removed without restarting, since the router can't change.
+pub fn create_routes(router: Router, config: &BotConfig) -> Router {
+    config
+        .endpoints
+        .keys()
+        .fold(router, |router, url| {
+            let path = url.clone();
+
+            router.route(
+                url,
                 get(
                     async move |Extension(pool): Extension<SqlitePool>,
                                 Json(body): Json<ChatBody>|
-                                -> Result<StreamBodyAs, (StatusCode, String)> {
-                        let history = get_history(include_str!("../sql/ChatHistoryFull.sql"), &pool, &body.user).await?;
-
-                        let response = resolve_task_stream(
-                            endpoint.task.clone(),
-                            config,
-                            config_json,
-                            Transform::new(),
-                            body.message.clone(),
-                            history,
-                            HashMap::new(),
+                                -> Result<StreamBodyAs, ApiError> {
+                        let loaded = current_config();
+                        let task = loaded
+                            .config
+                            .endpoints
+                            .get(&path)
+                            .ok_or_else(|| ApiError::NotFound(path.clone()))?
+                            .task
+                            .clone();
+
+                        let caller = limits::admit(
+                            &pool,
+                            &loaded.config,
+                            &body.user,
+                            body.user_id.as_deref(),
+                            body.guild.as_deref(),
+                            &path,
+                            true,
+                        )
+                        .await?;
+                        if body.regenerate.unwrap_or_default() {
+                            forget_last_exchange(&pool, body.history_key(), &body.message).await?;
+                        }
+                        let history = get_history(
+                            include_str!("../sql/ChatHistoryFull.sql"),
+                            &pool,
+                            body.history_key(),
                         )
-                        .await
-                        .map_err(internal_error_string)?;
+                        .await?;
+                        let history = with_context(history, &body.context);
+
+                        let response = limits::scoped(
+                            caller,
+                            resolve_task_stream(
+                                task,
+                                TaskContext {
+                                    config: loaded.config.clone(),
+                                    config_json: loaded.json.clone(),
+                                    history,
+                                    attachments: body.attachments.clone().unwrap_or_default(),
+                                },
+                                Transform::new(),
+                                body.message.clone(),
+                                body.args.clone().unwrap_or_default(),
+                            ),
+                        )
+                        .await?;
 
                         append_to_history!(pool, body, response);
                         json_nl_stream!(response)
                     },
                 ),
-            ))
-        })?
+            )
+        })
         .route(
             "/chat/help",
             get(
                 async move |Extension(pool): Extension<SqlitePool>,
                             Json(body): Json<ChatBody>|
-                            -> Result<StreamBodyAs, (StatusCode, String)> {
-                    let history = get_history(include_str!("../sql/ChatHistoryFull.sql"), &pool, &body.user).await?;
-
-                    let response = chat::chat_request(&help_prompt, body.message.clone(), &history, config.clone())
-                        .await
-                        .unwrap();
+                            -> Result<StreamBodyAs, ApiError> {
+                    let loaded = current_config();
+                    let caller = limits::admit(
+                        &pool,
+                        &loaded.config,
+                        &body.user,
+                        body.user_id.as_deref(),
+                        body.guild.as_deref(),
+                        "/chat/help",
+                        true,
+                    )
+                    .await?;
+                    if body.regenerate.unwrap_or_default() {
+                        forget_last_exchange(&pool, body.history_key(), &body.message).await?;
+                    }
+                    let history = get_history(
+                        include_str!("../sql/ChatHistoryFull.sql"),
+                        &pool,
+                        body.history_key(),
+                    )
+                    .await?;
+                    let history = with_context(history, &body.context);
+
+                    let response = limits::scoped(
+                        caller,
+                        chat::chat_request(
+                            "help",
+                            &loaded.help_prompt,
+                            body.message.clone().into(),
+                            &history,
+                            loaded.config.clone(),
+                        ),
+                    )
+                    .await?;
 
                     append_to_history!(pool, body, response);
                     json_nl_stream!(response)
@@ -271,98 +487,152 @@ pub fn create_routes(
             get(
                 async move |Extension(pool): Extension<SqlitePool>,
                             Json(body): Json<CategorizeBody>|
-                            -> Result<Json<CategorizeResponse>, (StatusCode, String)> {
-                    let history = get_history(include_str!("../sql/ChatHistoryCategorize.sql"), &pool, &body.user).await?;
-
-                    let category = chat::chat_request(
-                        &categorize_prompt,
-                        body.message.clone(),
-                        history
+                            -> Result<Json<CategorizeResponse>, ApiError> {
+                    let loaded = current_config();
+                    // turned away before categorizing costs anything, but only the endpoint takes
+                    // from the buckets
+                    let caller = limits::admit(
+                        &pool,
+                        &loaded.config,
+                        &body.user,
+                        body.user_id.as_deref(),
+                        body.guild.as_deref(),
+                        "/categorize",
+                        false,
+                    )
+                    .await?;
+                    let history = get_history(
+                        include_str!("../sql/ChatHistoryCategorize.sql"),
+                        &pool,
+                        body.history_key(),
+                    )
+                    .await?;
+                    let history = with_context(history, &body.context);
+
+                    // the categorizer only reads text, so it just needs to know images are there
+                    let images = body
+                        .attachments
+                        .iter()
+                        .flatten()
+                        .filter(|a| a.is_image())
+                        .count();
+                    let message = match images {
+                        0 => body.message.clone(),
+                        n => format!("{} [{n} image(s) attached]", body.message),
+                    };
+
+                    let category = limits::scoped(caller, async {
+                        let answer = chat::chat_request(
+                            "categorize",
+                            &loaded.categorize_prompt,
+                            message.into(),
+                            history
                                 .get(history.len().saturating_sub(2)..)
                                 .unwrap_or_default(),
-                                config.clone()
+                            loaded.config.clone(),
                         )
-                        .await.map_err(internal_error_string)?
-                        .try_collect::<Vec<String>>()
-                        .await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
-                        .concat();
+                        .await?;
+
+                        chat::collect(answer).await.map_err(ApiError::from)
+                    })
+                    .await?;
 
                     info!("<Categorize> Resolved category: {category}");
 
-                    if gen_config.endpoints.iter().any(|(_, c)| category == c.id) {
-                        Ok(Json(CategorizeResponse {
-                            category,
-                        }))
+                    if loaded.config.endpoints.values().any(|c| category == c.id) {
+                        Ok(Json(CategorizeResponse { category }))
                     } else {
                         Ok(Json(CategorizeResponse {
-                            category: gen_config.fallback_endpoint.clone()
+                            category: loaded.config.fallback_endpoint.clone(),
                         }))
                     }
-                }
-            )
+                },
+            ),
+        )
+        .route(
+            "/autocomplete",
+            get(
+                async move |Extension(pool): Extension<SqlitePool>,
+                            Json(body): Json<AutocompleteBody>|
+                            -> Result<Json<AutocompleteResponse>, ApiError> {
+                    let loaded = current_config();
+                    // suggestions don't take from the buckets, but they stop along with answers
+                    limits::admit(
+                        &pool,
+                        &loaded.config,
+                        &body.user,
+                        body.user_id.as_deref(),
+                        body.guild.as_deref(),
+                        "/autocomplete",
+                        false,
+                    )
+                    .await?;
+                    let choices =
+                        autocomplete(body, loaded.config.clone(), loaded.json.clone()).await?;
+
+                    Ok(Json(AutocompleteResponse { choices }))
+                },
+            ),
         )
-    )
 }
 
 #[async_recursion]
 async fn resolve_task_stream(
     task: String,
-    config: Arc<BotConfig>,
-    config_json: Arc<Value>,
+    task_context: TaskContext,
     transform: Transform,
     input: String,
-    history: Vec<ChatMessage>,
     args: HashMap<String, String>,
 ) -> Result<impl Stream<Item = Result<String, std::io::Error>>> {
     if task.starts_with("responses.") {
         let v = task.split('.').collect::<Vec<_>>();
         let response_config: ConfigResponse =
-            serde_json::from_value(config_json[v[0]][v[1]].clone())?;
+            serde_json::from_value(task_context.config_json[v[0]][v[1]].clone())?;
 
         info!("<{task}> Resolving as stream");
 
         let response_context = ResponseContext {
             input: input.clone(),
             datetime: datetime(),
-            props: config.props.clone(),
+            props: task_context.config.props.clone(),
             transform,
             args: args.clone(),
+            attachments: task_context.attachments.clone(),
         };
 
         let prompt = template_multiline(&response_config.prompt, &response_context)?;
 
         let response = chat::chat_request(
+            &task,
             &prompt,
-            args.get("input").unwrap_or(&input).clone(),
-            &history,
-            config,
+            response_message(
+                &response_config,
+                args.get("input").unwrap_or(&input).clone(),
+                &task_context.attachments,
+            ),
+            &task_context.history,
+            task_context.config.clone(),
         )
-        .await
-        .unwrap();
+        .await?;
 
         let footer = template(
             response_config.footer.unwrap_or_default(),
             &response_context,
         )?;
 
-        return Ok(async_stream::stream! {
-            let mut acc = String::new();
+        return Ok(with_footer(response.boxed(), footer));
+    } else if task.starts_with("tools.") {
+        let (response, footer) = resolve_tools(task, &task_context, transform, input, args).await?;
 
-            pin_mut!(response);
-
-            while let Some(Ok(part)) = response.next().await {
-                acc += &part;
-
-                let ret: Result<String, std::io::Error> = Ok(part.clone());
-
-                yield ret;
-            }
-
-            yield Ok(footer);
-        });
+        return Ok(with_footer(response, footer));
     } else if task.starts_with("macros.") {
         let v = task.split('.').collect::<Vec<_>>();
-        let macro_config: ConfigMacro = config.macros.get(v[1]).unwrap().clone();
+        let macro_config: ConfigMacro = task_context
+            .config
+            .macros
+            .get(v[1])
+            .ok_or_else(|| ApiError::BadConfig(format!("`{task}` isn't a macro")))?
+            .clone();
 
         let macro_start_context = MacroStartContext {
             input: input.clone(),
@@ -377,17 +647,18 @@ async fn resolve_task_stream(
                 response_context: ResponseContext {
                     input: input.clone(),
                     datetime: datetime(),
-                    props: config.props.clone(),
+                    props: task_context.config.props.clone(),
                     transform: transform.clone(),
                     args: args.clone(),
+                    attachments: task_context.attachments.clone(),
                 },
                 macro_: macro_start_context.clone(),
             };
 
             input_args = inst_args
                 .into_iter()
-                .map(|(k, v)| (k.clone(), template(v, &context).unwrap()))
-                .collect();
+                .map(|(k, v)| Ok((k.clone(), template(v, &context)?)))
+                .collect::<Result<_>>()?;
 
             input = input_args.get("input").unwrap_or(&input).clone();
 
@@ -405,11 +676,9 @@ async fn resolve_task_stream(
             // dont worry abt it :)
             (input, transform) = resolve_task(
                 inst.clone(),
-                config.clone(),
-                config_json.clone(),
+                &task_context,
                 transform.clone(),
                 input.clone(),
-                history.clone(),
                 input_args.clone(),
             )
             .await?;
@@ -423,29 +692,234 @@ async fn resolve_task_stream(
 
         return resolve_task_stream(
             macro_config.iter().last().unwrap().0.clone(),
-            config,
-            config_json,
+            task_context,
             transform,
             input,
-            history,
             input_args,
         )
         .await;
     } else {
         info!("<{task}> Streaming error");
-        return Err(eyre!("`{}` isn't a member of `responses` or `macros`. It can't be resolved to a stream and presented to the user.", task));
+        return Err(ApiError::BadConfig(format!("`{}` isn't a member of `responses`, `tools` or `macros`. It can't be resolved to a stream and presented to the user.", task)).into());
+    }
+}
+
+/// The user's message for a response, along with their images if the response accepts them
+fn response_message(
+    response_config: &ConfigResponse,
+    message: String,
+    attachments: &[ChatAttachment],
+) -> ChatContent {
+    if response_config.images.unwrap_or_default() {
+        ChatContent::with_images(message, attachments)
+    } else {
+        message.into()
     }
 }
 
+fn with_footer(
+    response: BoxStream<'static, Result<String, std::io::Error>>,
+    footer: String,
+) -> impl Stream<Item = Result<String, std::io::Error>> {
+    async_stream::stream! {
+        let mut response = response;
+
+        while let Some(part) = response.next().await {
+            match part {
+                Ok(part) => yield Ok(part),
+                // an answer that was cut off still gets its footer
+                Err(e) if error::truncated(&e) => {
+                    yield Ok(footer);
+                    yield Err(e);
+                    return;
+                }
+                Err(e) => {
+                    yield Err(e);
+                    return;
+                }
+            }
+        }
+
+        yield Ok(footer);
+    }
+}
+
+/// Offers the `tools.*` task's functions to the model and runs the `providers.*` tasks it calls,
+/// feeding their transforms back until it answers. Returns the answer stream and the footer.
+async fn resolve_tools(
+    task: String,
+    task_context: &TaskContext,
+    transform: Transform,
+    input: String,
+    args: HashMap<String, String>,
+) -> Result<(BoxStream<'static, Result<String, std::io::Error>>, String)> {
+    let config = &task_context.config;
+    let v = task.split('.').collect::<Vec<_>>();
+    let tool_config: ConfigTool = config
+        .tools
+        .as_ref()
+        .and_then(|tools| tools.get(v[1]))
+        .ok_or_else(|| ApiError::BadConfig(format!("`{task}` isn't a tool")))?
+        .clone();
+
+    let context = ResponseContext {
+        input: input.clone(),
+        datetime: datetime(),
+        props: config.props.clone(),
+        transform,
+        args: args.clone(),
+        attachments: task_context.attachments.clone(),
+    };
+
+    let prompt = template_multiline(&tool_config.prompt, &context)?;
+    let footer = template(tool_config.footer.clone().unwrap_or_default(), &context)?;
+
+    let functions = tool_config
+        .functions
+        .iter()
+        .map(|(name, function)| {
+            json!({
+                "type": "function",
+                "function": {
+                    "name": name,
+                    "description": function.description,
+                    "parameters": {
+                        "type": "object",
+                        "properties": function
+                            .parameters
+                            .iter()
+                            .map(|(param, options)| (param.clone(), json!({
+                                "type": "string",
+                                "description": options.description,
+                            })))
+                            .collect::<serde_json::Map<_, _>>(),
+                        "required": function
+                            .parameters
+                            .iter()
+                            .filter(|(_, options)| options.required.unwrap_or(true))
+                            .map(|(param, _)| param)
+                            .collect::<Vec<_>>(),
+                    },
+                },
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let history = &task_context.history;
+    let mut messages = history
+        .get(history.len().saturating_sub(config.message_history)..)
+        .unwrap_or_default()
+        .iter()
+        .map(|message| json!(message))
+        .chain([
+            json!(ChatMessage::new(ChatRole::System, prompt)),
+            json!(ChatMessage::new(
+                ChatRole::User,
+                args.get("input").unwrap_or(&input).as_str()
+            )),
+        ])
+        .collect::<Vec<_>>();
+
+    let max_iterations = tool_config
+        .max_iterations
+        .unwrap_or(DEFAULT_TOOL_ITERATIONS);
+
+    for iteration in 0..=max_iterations {
+        let calls =
+            match chat::tool_request(&task, &messages, &functions, iteration == max_iterations)
+                .await?
+            {
+                ToolTurn::Answer(response) => {
+                    info!("<{task}> Answering after {iteration} round(s) of calls");
+                    return Ok((response, footer));
+                }
+                ToolTurn::Calls(calls) => calls,
+            };
+
+        messages.push(json!({
+            "role": ChatRole::Assistant,
+            "content": null,
+            "tool_calls": calls.iter().map(|call| json!({
+                "id": call.id,
+                "type": "function",
+                "function": { "name": call.name, "arguments": call.arguments },
+            })).collect::<Vec<_>>(),
+        }));
+
+        for call in calls {
+            info!(
+                "<{task}> ({}/{max_iterations}) Calling `{}` with {}",
+                iteration + 1,
+                call.name,
+                call.arguments
+            );
+
+            let result = call_tool(&tool_config, &call, task_context, input.clone())
+                .await
+                .unwrap_or_else(|e| {
+                    warn!("<{task}> `{}` failed: {e:#}", call.name);
+                    json!({ "error": e.to_string() })
+                });
+
+            messages.push(json!(ChatMessage::tool(call.id, result.to_string())));
+        }
+    }
+
+    Err(eyre!(
+        "<{task}> No answer after {max_iterations} round(s) of calls"
+    ))
+}
+
+/// Runs the `providers.*` task behind a function call, responding with its transform
+async fn call_tool(
+    tool_config: &ConfigTool,
+    call: &chat::ToolCall,
+    task_context: &TaskContext,
+    input: String,
+) -> Result<Value> {
+    let function = tool_config
+        .functions
+        .get(&call.name)
+        .context(format!("{:?} isn't a function", call.name))?;
+
+    let args = serde_json::from_str::<serde_json::Map<String, Value>>(&call.arguments)
+        .context("Arguments aren't a JSON object")?
+        .into_iter()
+        .map(|(k, v)| match v {
+            Value::String(v) => (k, v),
+            v => (k, v.to_string()),
+        })
+        .collect::<HashMap<_, _>>();
+
+    let input = args.get("input").cloned().unwrap_or(input);
+
+    let (_, transform) = resolve_task(
+        function.task.clone(),
+        task_context,
+        Transform::new(),
+        input,
+        args,
+    )
+    .await?;
+
+    Ok(json!(transform))
+}
+
+#[async_recursion]
 async fn resolve_task(
     task: String,
-    config: Arc<BotConfig>,
-    config_json: Arc<Value>,
+    task_context: &TaskContext,
     mut transform: Transform,
     input: String,
-    history: Vec<ChatMessage>,
     args: HashMap<String, String>,
 ) -> Result<(String, Transform)> {
+    let TaskContext {
+        config,
+        config_json,
+        history,
+        attachments,
+    } = task_context;
+
     if task.starts_with("responses.") {
         let v = task.split('.').collect::<Vec<_>>();
         let response_config: ConfigResponse =
@@ -457,20 +931,24 @@ async fn resolve_task(
             props: config.props.clone(),
             transform: transform.clone(),
             args: args.clone(),
+            attachments: attachments.clone(),
         };
 
         let prompt = template_multiline(&response_config.prompt, &context)?;
 
-        let reponse = chat::chat_request(
+        let answer = chat::chat_request(
+            &task,
             &prompt,
-            args.get("input").unwrap_or(&input).clone(),
-            &history,
-            config,
+            response_message(
+                &response_config,
+                args.get("input").unwrap_or(&input).clone(),
+                attachments,
+            ),
+            history,
+            config.clone(),
         )
-        .await?
-        .try_collect::<Vec<String>>()
-        .await?
-        .concat();
+        .await?;
+        let reponse = chat::collect(answer).await?;
 
         let response_context_with_output = ResponseContextWithOutput {
             response_context: context,
@@ -482,32 +960,30 @@ async fn resolve_task(
             &response_context_with_output,
         )?;
 
-        response_config
-            .transform
-            .unwrap_or_default()
-            .into_iter()
-            .for_each(|(k, v)| {
-                transform.insert(
-                    k.clone(),
-                    template(v, &response_context_with_output).unwrap(),
-                );
-            });
+        for (k, v) in response_config.transform.unwrap_or_default() {
+            transform.insert(k, template(v, &response_context_with_output)?);
+        }
 
         Ok((reponse + &footer, transform))
+    } else if task.starts_with("tools.") {
+        let (response, footer) =
+            resolve_tools(task, task_context, transform.clone(), input, args).await?;
+
+        let output = chat::collect(response).await?;
+
+        Ok((output + &footer, transform))
     } else if task.starts_with("providers.") {
         let v = task.split('.').collect::<Vec<_>>();
         let provider_config: ConfigProvider =
             serde_json::from_value(config_json[v[0]][v[1]].clone())?;
 
-        let provider_def: Provider = serde_json::from_str(
-            &cat(PathBuf::from(var("PROVIDERS_PATH").unwrap())
-                .join(format!("{}.json", provider_config.provider)))
-            .context(format!("{:?} is not a provider", provider_config.provider))?,
-        )
-        .context(format!(
-            "{:?} is an invalid provider",
-            provider_config.provider
-        ))?;
+        let registry = registry::registry();
+        let provider_def = registry
+            .get(&provider_config.provider)
+            .ok_or_else(|| {
+                ApiError::BadConfig(format!("{:?} is not a provider", provider_config.provider))
+            })?
+            .clone();
 
         info!(
             "<{task}> Resolving with provider `{provider}`",
@@ -521,101 +997,216 @@ async fn resolve_task(
                 props: config.props.clone(),
                 transform: transform.clone(),
                 args,
+                attachments: attachments.clone(),
             },
             env: env::vars()
                 .filter(|(k, _)| provider_def.env.clone().unwrap_or_default().contains(k))
                 .collect(),
         };
 
-        let url = template(&provider_def.url, &context)?;
+        let url = provider_def
+            .url
+            .as_ref()
+            .map(|url| template(url, &context))
+            .transpose()?
+            .unwrap_or_default();
 
         let props: HashMap<String, String> = provider_config
             .props
             .into_iter()
-            .map(|(k, v)| (k, template(&v, &context).unwrap()))
-            .collect();
+            .map(|(k, v)| Ok((k, template(&v, &context)?)))
+            .collect::<Result<_>>()?;
 
-        let resolved_props: HashMap<String, ProviderPropOptions> = props
+        // Props are checked against the provider's rules when the registry is loaded
+        let resolved_props = props
             .into_iter()
             .map(|(prop, prop_val)| {
                 let rules = provider_def
                     .prop_rules
                     .iter()
                     .find(|rule| rule.props.contains(&prop))
-                    .context(format!("No prop rule found for {prop:?}"))
-                    .unwrap();
+                    .context(format!("No prop rule found for {prop:?}"))?;
 
-                (
+                Ok((
                     prop,
                     ProviderPropOptions {
                         required: rules.required,
                         redirect: rules.redirect.clone(),
                         value: prop_val,
                     },
+                ))
+            })
+            .collect::<Result<HashMap<_, _>>>()?;
+
+        let auth = provider_def
+            .auth
+            .as_ref()
+            .map(|auth| auth::render_auth(auth, |source| template(source, &context)))
+            .transpose()?;
+
+        let secrets = context
+            .env
+            .values()
+            .cloned()
+            .chain(auth.iter().flat_map(auth::secrets))
+            .collect::<Vec<_>>();
+
+        for (k, v) in resolved_props
+            .iter()
+            .filter(|(_, prop_options)| prop_options.required)
+        {
+            info!(
+                "<{task}> Required prop {k:?} = `{v}`",
+                v = auth::redact(&template(&v.value, &context)?, &secrets)
+            );
+        }
+
+        let query = merge_request_parts!(query, Query, resolved_props, provider_def, context)?;
+        let body = merge_request_parts!(body, Body, resolved_props, provider_def, context)?;
+        let headers =
+            merge_request_parts!(headers, Headers, resolved_props, provider_def, context)?;
+
+        let raw_body = provider_def
+            .raw_body
+            .as_ref()
+            .map(|raw_body| {
+                template(
+                    raw_body,
+                    &ProviderBodyContext {
+                        response_context: context.clone(),
+                        body: body.clone(),
+                    },
                 )
             })
-            .collect();
+            .transpose()?;
 
-        let all_required = provider_def
-            .prop_rules
-            .iter()
-            .filter(|rule| rule.required)
-            .map(|rule| rule.props.clone())
-            .flatten()
-            .collect::<HashSet<_>>();
-
-        all_required.iter().for_each(|required_prop| {
-            if !resolved_props
-                .iter()
-                .filter(|(_, prop_options)| prop_options.required)
-                .any(|(prop, _)| prop == required_prop)
-            {
-                panic!("Required prop `{}` not found", required_prop);
-            }
-        });
+        if provider_def.builtin.is_none() {
+            info!(
+                "<{task}> {method:?} {url:?}",
+                method = provider_def.method.clone().unwrap_or_default(),
+                url = auth::redact(&url, &secrets)
+            );
+        }
 
-        resolved_props
-            .iter()
-            .filter(|(_, prop_options)| prop_options.required)
-            .for_each(|(k, v)| {
-                info!(
-                    "<{task}> Required prop {k:?} = `{v}`",
-                    v = template(&v.value, &context).unwrap()
+        // Builtin settings only see the operator's own values, never anything a user typed
+        let builtin_context = json!({ "env": &context.env, "props": &config.props });
+        let builtin = provider_def
+            .builtin
+            .as_ref()
+            .map(|builtin| {
+                provider::render_builtin(builtin, |source| template(source, &builtin_context))
+            })
+            .transpose()?;
+
+        let request = ProviderRequest {
+            url,
+            query,
+            body,
+            raw_body,
+            headers,
+            auth,
+            builtin,
+        };
+
+        let cache = provider_config
+            .cache_ttl
+            .zip(cache::cache())
+            .map(|(ttl, cache)| {
+                let key = cache::cache_key(
+                    &provider_config.provider,
+                    &provider_def.method.clone().unwrap_or_default(),
+                    &request,
                 );
+                (cache, key, ttl)
             });
 
-        let query = merge_request_parts!(query, Query, resolved_props, provider_def, context);
-        let body = merge_request_parts!(body, Body, resolved_props, provider_def, context);
-        let headers = merge_request_parts!(headers, Headers, resolved_props, provider_def, context);
+        let cached = match &cache {
+            Some((cache, key, _)) => cache.get(key).await?,
+            None => None,
+        };
 
-        info!("<{task}> GET {url:?}");
+        let ProviderResponse { status, response } = match cached {
+            Some(cached) => {
+                info!("<{task}> Cache hit");
+                cached
+            }
+            None => {
+                let response = provider::execute(&provider_config.provider, &provider_def, request)
+                    .await
+                    .map_err(|e| ApiError::Provider(format!("<{task}> {e:#}")))?;
+
+                // empty and passed-through error responses would be stuck for the whole TTL
+                if let Some((cache, key, ttl)) =
+                    cache.filter(|_| provider::accepted(&provider_def, response.status))
+                {
+                    info!("<{task}> Cache miss, caching for {ttl}s");
+                    cache.set(key, &response, ttl).await?;
+                }
 
-        let response: Value = reqwest::Client::new()
-            .get(url)
-            .json(&body)
-            .query(&query)
-            .headers(HeaderMap::from_iter(headers.iter().map(|(k, v)| {
-                (
-                    HeaderName::from_bytes(k.as_bytes()).unwrap(),
-                    HeaderValue::from_str(v).unwrap(),
-                )
-            })))
-            .send()
-            .await?
-            .json()
-            .await?;
+                response
+            }
+        };
 
         let transform_context = ProviderTransformContext {
             response_context: context.clone(),
+            status,
             response,
         };
 
-        provider_config.transform.into_iter().for_each(|(k, v)| {
-            transform.insert(k.clone(), template(v, &transform_context).unwrap());
-        });
+        for (k, v) in provider_config.transform {
+            transform.insert(k, template(v, &transform_context)?);
+        }
 
         Ok((String::new(), transform))
     } else {
-        Err(eyre!("`{}` isn't a member of `responses` or `searchQueries`. It can't be run as an intermediate task.", task))
+        Err(ApiError::BadConfig(format!("`{}` isn't a member of `responses`, `providers` or `tools`. It can't be run as an intermediate task.", task)).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use architectury::prelude::*;
+    use openchad_schemas::chat::{ChatMessage, ChatRole};
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    use super::{append_history, forget_last_exchange};
+    use crate::get_history;
+
+    #[tokio::test]
+    async fn regenerating_replaces_the_last_exchange() -> Result<()> {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await?;
+        sqlx::migrate!().run(&pool).await?;
+
+        for (role, message) in [
+            (ChatRole::User, "ann: hi"),
+            (ChatRole::Assistant, "Hello!"),
+            (ChatRole::User, "ann: tell me a joke"),
+            (ChatRole::Assistant, "No."),
+        ] {
+            append_history(&pool, "ann".into(), ChatMessage::new(role, message)).await?;
+        }
+        append_history(
+            &pool,
+            "bob".into(),
+            ChatMessage::new(ChatRole::User, "ann: tell me a joke"),
+        )
+        .await?;
+
+        forget_last_exchange(&pool, "ann", "ann: tell me a joke").await?;
+        // nothing matches, so nothing is dropped
+        forget_last_exchange(&pool, "ann", "ann: something else").await?;
+
+        let history = |user| get_history(include_str!("../sql/ChatHistoryFull.sql"), &pool, user);
+        let ann = history("ann").await?;
+        assert_eq!(
+            ann.iter().map(|m| m.content.text()).collect::<Vec<_>>(),
+            ["ann: hi", "Hello!"]
+        );
+        assert_eq!(history("bob").await?.len(), 1);
+
+        Ok(())
     }
 }
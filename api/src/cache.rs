@@ -0,0 +1,317 @@
+use std::collections::{BTreeMap, HashMap};
+use std::env::var;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use architectury::prelude::*;
+use eyre::{eyre, Context, ContextCompat};
+use once_cell::sync::OnceCell;
+use openchad_schemas::provider::Method;
+use openchad_schemas::ProviderCacheStats;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::{Row, SqlitePool};
+use tokio::sync::Mutex;
+
+use crate::provider::{ProviderRequest, ProviderResponse};
+
+static CACHE: OnceCell<ProviderCache> = OnceCell::new();
+
+enum CacheStorage {
+    Memory(Mutex<HashMap<String, (Instant, ProviderResponse)>>),
+    Sqlite(SqlitePool),
+}
+
+pub struct ProviderCache {
+    storage: CacheStorage,
+    bypass: bool,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Sets up the provider cache from `PROVIDER_CACHE` (`memory` or `sqlite`, defaults to `memory`).
+/// Setting `PROVIDER_CACHE_BYPASS` skips the cache entirely, which is useful for debugging.
+pub fn init(pool: SqlitePool) -> Result<()> {
+    let cache = ProviderCache::from_env(pool)?;
+
+    if cache.bypass {
+        warn!("Provider cache is bypassed");
+    }
+
+    CACHE
+        .set(cache)
+        .map_err(|_| eyre!("Provider cache was already initialized"))
+}
+
+/// The cache to use for provider requests, if it's enabled
+pub fn cache() -> Option<&'static ProviderCache> {
+    CACHE.get().filter(|cache| !cache.bypass)
+}
+
+pub async fn stats() -> Result<ProviderCacheStats> {
+    CACHE
+        .get()
+        .context("Provider cache isn't initialized")?
+        .stats()
+        .await
+}
+
+pub async fn clear() -> Result<()> {
+    CACHE
+        .get()
+        .context("Provider cache isn't initialized")?
+        .clear()
+        .await
+}
+
+/// Keys on everything that's sent. Headers and auth mostly carry credentials, so they're hashed
+/// rather than stored
+pub fn cache_key(provider: &str, method: &Method, request: &ProviderRequest) -> String {
+    let credentials = json!({
+        "headers": request.headers.iter().collect::<BTreeMap<_, _>>(),
+        "auth": request.auth,
+    });
+
+    json!({
+        "provider": provider,
+        "method": method,
+        "url": request.url,
+        "query": request.query.iter().collect::<BTreeMap<_, _>>(),
+        "body": request.body.iter().collect::<BTreeMap<_, _>>(),
+        "rawBody": request.raw_body,
+        "credentials": format!("{:x}", Sha256::digest(credentials.to_string())),
+    })
+    .to_string()
+}
+
+impl ProviderCache {
+    fn new(storage: CacheStorage, bypass: bool) -> Self {
+        Self {
+            storage,
+            bypass,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn from_env(pool: SqlitePool) -> Result<Self> {
+        let storage = match var("PROVIDER_CACHE").as_deref() {
+            Ok("memory") | Err(_) => CacheStorage::Memory(Mutex::new(HashMap::new())),
+            Ok("sqlite") => CacheStorage::Sqlite(pool),
+            Ok(other) => return Err(eyre!("Unknown provider cache storage {other:?}")),
+        };
+
+        Ok(Self::new(storage, var("PROVIDER_CACHE_BYPASS").is_ok()))
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<ProviderResponse>> {
+        let cached = match &self.storage {
+            CacheStorage::Memory(entries) => entries
+                .lock()
+                .await
+                .get(key)
+                .filter(|(expires, _)| Instant::now() < *expires)
+                .map(|(_, response)| response.clone()),
+            CacheStorage::Sqlite(pool) => {
+                sqlx::query(include_str!("../sql/ProviderCacheSelect.sql"))
+                    .bind(key)
+                    .fetch_optional(pool)
+                    .await
+                    .context("Failed to query provider cache")?
+                    .map(|row| serde_json::from_str(row.get("response")))
+                    .transpose()?
+            }
+        };
+
+        if cached.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+
+        Ok(cached)
+    }
+
+    pub async fn set(&self, key: String, response: &ProviderResponse, ttl: u64) -> Result<()> {
+        match &self.storage {
+            CacheStorage::Memory(entries) => {
+                let mut entries = entries.lock().await;
+                let now = Instant::now();
+                entries.retain(|_, (expires, _)| now < *expires);
+                entries.insert(key, (now + Duration::from_secs(ttl), response.clone()));
+            }
+            CacheStorage::Sqlite(pool) => {
+                sqlx::query(include_str!("../sql/ProviderCachePrune.sql"))
+                    .execute(pool)
+                    .await
+                    .context("Failed to prune provider cache")?;
+
+                sqlx::query(include_str!("../sql/ProviderCacheInsert.sql"))
+                    .bind(key)
+                    .bind(serde_json::to_string(response)?)
+                    .bind(format!("+{ttl} seconds"))
+                    .execute(pool)
+                    .await
+                    .context("Failed to insert into provider cache")?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn clear(&self) -> Result<()> {
+        match &self.storage {
+            CacheStorage::Memory(entries) => entries.lock().await.clear(),
+            CacheStorage::Sqlite(pool) => {
+                sqlx::query("delete from ProviderCache")
+                    .execute(pool)
+                    .await
+                    .context("Failed to clear provider cache")?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn stats(&self) -> Result<ProviderCacheStats> {
+        let (storage, entries) = match &self.storage {
+            CacheStorage::Memory(entries) => {
+                let now = Instant::now();
+                let entries = entries.lock().await;
+                (
+                    "memory",
+                    entries
+                        .values()
+                        .filter(|(expires, _)| now < *expires)
+                        .count() as u64,
+                )
+            }
+            CacheStorage::Sqlite(pool) => (
+                "sqlite",
+                sqlx::query(include_str!("../sql/ProviderCacheCount.sql"))
+                    .fetch_one(pool)
+                    .await
+                    .context("Failed to count provider cache entries")?
+                    .get::<i64, _>("entries") as u64,
+            ),
+        };
+
+        Ok(ProviderCacheStats {
+            storage: storage.into(),
+            bypass: self.bypass,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use architectury::prelude::*;
+    use openchad_schemas::provider::Method;
+    use serde_json::json;
+    use sqlx::SqlitePool;
+    use tokio::sync::Mutex;
+
+    use super::{cache_key, CacheStorage, ProviderCache};
+    use crate::provider::{ProviderRequest, ProviderResponse};
+
+    fn memory() -> ProviderCache {
+        ProviderCache::new(CacheStorage::Memory(Mutex::new(HashMap::new())), false)
+    }
+
+    fn request(body: &[(&str, &str)]) -> ProviderRequest {
+        ProviderRequest {
+            url: "https://api.example.com/search".into(),
+            query: HashMap::from([("q".into(), "volcano".into()), ("count".into(), "5".into())]),
+            body: body
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            raw_body: None,
+            headers: HashMap::from([("X-Api-Key".into(), "secret".into())]),
+            auth: None,
+            builtin: None,
+        }
+    }
+
+    fn response(answer: &str) -> ProviderResponse {
+        ProviderResponse {
+            status: 200,
+            response: json!({ "answer": answer }),
+        }
+    }
+
+    #[tokio::test]
+    async fn hits_only_the_same_request() -> Result<()> {
+        let cache = memory();
+        let key = cache_key(
+            "search",
+            &Method::Post,
+            &request(&[("lang", "en"), ("safe", "on")]),
+        );
+        cache.set(key, &response("lava"), 60).await?;
+
+        // maps are keyed in order, so the same request always has the same key
+        let same = cache_key(
+            "search",
+            &Method::Post,
+            &request(&[("safe", "on"), ("lang", "en")]),
+        );
+        let other_body = cache_key("search", &Method::Post, &request(&[("lang", "de")]));
+        let other_method = cache_key(
+            "search",
+            &Method::Put,
+            &request(&[("lang", "en"), ("safe", "on")]),
+        );
+
+        assert_eq!(cache.get(&same).await?.unwrap().response["answer"], "lava");
+        assert!(cache.get(&other_body).await?.is_none());
+        assert!(cache.get(&other_method).await?.is_none());
+        assert!(!same.contains("secret"));
+
+        let stats = cache.stats().await?;
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 2, 1));
+
+        cache.clear().await?;
+        assert!(cache.get(&same).await?.is_none());
+        assert_eq!(cache.stats().await?.entries, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn entries_expire_after_their_ttl() -> Result<()> {
+        let cache = memory();
+        let key = cache_key("search", &Method::Get, &request(&[]));
+        cache.set(key.clone(), &response("lava"), 1).await?;
+
+        assert!(cache.get(&key).await?.is_some());
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert!(cache.get(&key).await?.is_none());
+
+        let stats = cache.stats().await?;
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 1, 0));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bypass_is_read_from_the_env() -> Result<()> {
+        let pool = SqlitePool::connect_lazy("sqlite::memory:")?;
+
+        std::env::set_var("PROVIDER_CACHE_BYPASS", "1");
+        let bypassed = ProviderCache::from_env(pool.clone());
+        std::env::remove_var("PROVIDER_CACHE_BYPASS");
+        let cached = ProviderCache::from_env(pool)?;
+
+        assert!(bypassed?.stats().await?.bypass);
+        assert!(!cached.stats().await?.bypass);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,325 @@
+use architectury::prelude::*;
+use chrono::{Duration, Local, Months, NaiveDate};
+use eyre::{eyre, ContextCompat};
+use serde_json::{json, Value};
+
+/// Keeps the evaluator from chewing on whole paragraphs a model decided to pass along
+const MAX_EXPRESSION: usize = 256;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Dimension {
+    Length,
+    Mass,
+    Volume,
+    Area,
+    Time,
+    Speed,
+    Data,
+    Energy,
+    Temperature,
+}
+
+/// `base = (value + offset) * factor`, so temperatures can share the table with everything else
+struct Unit {
+    names: &'static [&'static str],
+    dimension: Dimension,
+    factor: f64,
+    offset: f64,
+}
+
+const fn linear(names: &'static [&'static str], dimension: Dimension, factor: f64) -> Unit {
+    Unit {
+        names,
+        dimension,
+        factor,
+        offset: 0.0,
+    }
+}
+
+#[rustfmt::skip]
+const UNITS: &[Unit] = &[
+    linear(&["m", "meter", "meters", "metre", "metres"], Dimension::Length, 1.0),
+    linear(&["km", "kilometer", "kilometers", "kilometre", "kilometres"], Dimension::Length, 1000.0),
+    linear(&["cm", "centimeter", "centimeters"], Dimension::Length, 0.01),
+    linear(&["mm", "millimeter", "millimeters"], Dimension::Length, 0.001),
+    linear(&["mi", "mile", "miles"], Dimension::Length, 1609.344),
+    linear(&["yd", "yard", "yards"], Dimension::Length, 0.9144),
+    linear(&["ft", "foot", "feet"], Dimension::Length, 0.3048),
+    linear(&["in", "inch", "inches"], Dimension::Length, 0.0254),
+    linear(&["nmi", "nautical mile", "nautical miles"], Dimension::Length, 1852.0),
+    linear(&["kg", "kilogram", "kilograms"], Dimension::Mass, 1.0),
+    linear(&["g", "gram", "grams"], Dimension::Mass, 0.001),
+    linear(&["mg", "milligram", "milligrams"], Dimension::Mass, 0.000001),
+    linear(&["t", "tonne", "tonnes"], Dimension::Mass, 1000.0),
+    linear(&["lb", "lbs", "pound", "pounds"], Dimension::Mass, 0.45359237),
+    linear(&["oz", "ounce", "ounces"], Dimension::Mass, 0.028349523125),
+    linear(&["st", "stone"], Dimension::Mass, 6.35029318),
+    linear(&["l", "liter", "liters", "litre", "litres"], Dimension::Volume, 1.0),
+    linear(&["ml", "milliliter", "milliliters"], Dimension::Volume, 0.001),
+    linear(&["m3", "cubic meter", "cubic meters"], Dimension::Volume, 1000.0),
+    linear(&["gal", "gallon", "gallons"], Dimension::Volume, 3.785411784),
+    linear(&["qt", "quart", "quarts"], Dimension::Volume, 0.946352946),
+    linear(&["pt", "pint", "pints"], Dimension::Volume, 0.473176473),
+    linear(&["cup", "cups"], Dimension::Volume, 0.2365882365),
+    linear(&["floz", "fl oz", "fluid ounce", "fluid ounces"], Dimension::Volume, 0.0295735295625),
+    linear(&["tbsp", "tablespoon", "tablespoons"], Dimension::Volume, 0.01478676478125),
+    linear(&["tsp", "teaspoon", "teaspoons"], Dimension::Volume, 0.00492892159375),
+    linear(&["m2", "square meter", "square meters"], Dimension::Area, 1.0),
+    linear(&["km2", "square kilometer", "square kilometers"], Dimension::Area, 1_000_000.0),
+    linear(&["ft2", "sqft", "square foot", "square feet"], Dimension::Area, 0.09290304),
+    linear(&["mi2", "square mile", "square miles"], Dimension::Area, 2_589_988.110336),
+    linear(&["acre", "acres"], Dimension::Area, 4046.8564224),
+    linear(&["ha", "hectare", "hectares"], Dimension::Area, 10_000.0),
+    linear(&["s", "sec", "second", "seconds"], Dimension::Time, 1.0),
+    linear(&["ms", "millisecond", "milliseconds"], Dimension::Time, 0.001),
+    linear(&["min", "minute", "minutes"], Dimension::Time, 60.0),
+    linear(&["h", "hr", "hour", "hours"], Dimension::Time, 3600.0),
+    linear(&["day", "days"], Dimension::Time, 86_400.0),
+    linear(&["week", "weeks"], Dimension::Time, 604_800.0),
+    linear(&["year", "years"], Dimension::Time, 31_557_600.0),
+    linear(&["m/s"], Dimension::Speed, 1.0),
+    linear(&["km/h", "kph"], Dimension::Speed, 1000.0 / 3600.0),
+    linear(&["mph"], Dimension::Speed, 0.44704),
+    linear(&["ft/s"], Dimension::Speed, 0.3048),
+    linear(&["kn", "knot", "knots"], Dimension::Speed, 1852.0 / 3600.0),
+    linear(&["b", "byte", "bytes"], Dimension::Data, 1.0),
+    linear(&["bit", "bits"], Dimension::Data, 0.125),
+    linear(&["kb", "kilobyte", "kilobytes"], Dimension::Data, 1e3),
+    linear(&["mb", "megabyte", "megabytes"], Dimension::Data, 1e6),
+    linear(&["gb", "gigabyte", "gigabytes"], Dimension::Data, 1e9),
+    linear(&["tb", "terabyte", "terabytes"], Dimension::Data, 1e12),
+    linear(&["kib"], Dimension::Data, 1024.0),
+    linear(&["mib"], Dimension::Data, 1_048_576.0),
+    linear(&["gib"], Dimension::Data, 1_073_741_824.0),
+    linear(&["tib"], Dimension::Data, 1_099_511_627_776.0),
+    linear(&["j", "joule", "joules"], Dimension::Energy, 1.0),
+    linear(&["kj", "kilojoule", "kilojoules"], Dimension::Energy, 1000.0),
+    linear(&["cal", "calorie", "calories"], Dimension::Energy, 4.184),
+    linear(&["kcal", "kilocalorie", "kilocalories"], Dimension::Energy, 4184.0),
+    linear(&["wh"], Dimension::Energy, 3600.0),
+    linear(&["kwh"], Dimension::Energy, 3_600_000.0),
+    Unit { names: &["°c", "c", "celsius"], dimension: Dimension::Temperature, factor: 1.0, offset: 273.15 },
+    Unit { names: &["°f", "f", "fahrenheit"], dimension: Dimension::Temperature, factor: 5.0 / 9.0, offset: 459.67 },
+    Unit { names: &["k", "kelvin"], dimension: Dimension::Temperature, factor: 1.0, offset: 0.0 },
+];
+
+fn unit(name: &str) -> Option<&'static Unit> {
+    let name = name.trim().trim_end_matches('.');
+    UNITS.iter().find(|unit| unit.names.contains(&name))
+}
+
+/// Splits `5 km`, `5km` or `5 fl oz` into the amount and the longest unit that ends it
+fn split_unit(source: &str) -> Option<(&str, &'static Unit)> {
+    source
+        .char_indices()
+        .map(|(i, _)| i)
+        .filter(|i| {
+            source[..*i].ends_with(|c: char| {
+                c.is_whitespace() || c.is_ascii_digit() || c == ')' || c == '.'
+            })
+        })
+        .find_map(|i| unit(&source[i..]).map(|unit| (source[..i].trim(), unit)))
+}
+
+/// Drops floating point noise like `0.30000000000000004`
+fn format_number(value: f64) -> String {
+    let rounded = (value * 1e10).round() / 1e10;
+
+    if rounded.fract() == 0.0 && rounded.abs() < 1e15 {
+        format!("{rounded:.0}")
+    } else {
+        rounded.to_string()
+    }
+}
+
+fn eval(expression: &str) -> Result<f64> {
+    let value = meval::eval_str(expression.replace('×', "*").replace('÷', "/"))
+        .map_err(|e| eyre!("Invalid expression {expression:?}: {e}"))?;
+
+    if value.is_finite() {
+        Ok(value)
+    } else {
+        Err(eyre!("{expression:?} isn't a finite number"))
+    }
+}
+
+fn conversion(expression: &str) -> Result<Option<Value>> {
+    let Some((from, to)) = expression
+        .rsplit_once(" to ")
+        .or_else(|| expression.rsplit_once(" in "))
+    else {
+        return Ok(None);
+    };
+
+    let Some(to) = unit(to) else {
+        return Ok(None);
+    };
+
+    let (amount, from) =
+        split_unit(from.trim()).context(format!("Couldn't find a unit in {from:?}"))?;
+
+    if from.dimension != to.dimension {
+        return Err(eyre!(
+            "Can't convert {} to {} ({:?} to {:?})",
+            from.names[0],
+            to.names[0],
+            from.dimension,
+            to.dimension
+        ));
+    }
+
+    let base = (eval(amount)? + from.offset) * from.factor;
+    let value = base / to.factor - to.offset;
+
+    Ok(Some(json!({
+        "kind": "conversion",
+        "expression": expression,
+        "result": format!("{} {}", format_number(value), to.names[0]),
+        "value": value,
+        "unit": to.names[0],
+    })))
+}
+
+fn parse_date(token: &str, today: NaiveDate) -> Option<NaiveDate> {
+    match token {
+        "today" | "now" => Some(today),
+        "tomorrow" => today.succ_opt(),
+        "yesterday" => today.pred_opt(),
+        _ => NaiveDate::parse_from_str(token, "%Y-%m-%d").ok(),
+    }
+}
+
+fn shift(date: NaiveDate, amount: i64, unit: &str) -> Result<NaiveDate> {
+    let months = |months: i64| {
+        let delta = Months::new(months.unsigned_abs() as u32);
+        if months < 0 {
+            date.checked_sub_months(delta)
+        } else {
+            date.checked_add_months(delta)
+        }
+    };
+
+    match unit.trim_end_matches('s') {
+        "day" | "d" => date.checked_add_signed(Duration::days(amount)),
+        "week" | "w" => date.checked_add_signed(Duration::weeks(amount)),
+        "month" => months(amount),
+        "year" | "y" => months(amount * 12),
+        _ => return Err(eyre!("Unknown date unit {unit:?}")),
+    }
+    .context("Date is out of range")
+}
+
+/// `2023-04-12 + 30 days`, `today - 2 weeks + 1 day`, or `2023-12-25 - today`
+fn date_math(expression: &str, today: NaiveDate) -> Result<Option<Value>> {
+    let tokens = expression.split_whitespace().collect::<Vec<_>>();
+
+    let Some(mut date) = tokens.first().and_then(|token| parse_date(token, today)) else {
+        return Ok(None);
+    };
+
+    if let [_, "-", end] = tokens.as_slice() {
+        if let Some(end) = parse_date(end, today) {
+            let days = date.signed_duration_since(end).num_days();
+
+            return Ok(Some(json!({
+                "kind": "date",
+                "expression": expression,
+                "result": format!("{days} days"),
+                "value": days,
+            })));
+        }
+    }
+
+    let mut rest = &tokens[1..];
+
+    while let [op, amount, unit, tail @ ..] = rest {
+        let sign = match *op {
+            "+" => 1,
+            "-" => -1,
+            _ => return Err(eyre!("Expected `+` or `-`, found {op:?}")),
+        };
+        let amount = amount
+            .parse::<i64>()
+            .map_err(|_| eyre!("{amount:?} isn't a whole number"))?;
+
+        date = shift(date, sign * amount, unit)?;
+        rest = tail;
+    }
+
+    if !rest.is_empty() {
+        return Err(eyre!("Couldn't read {:?} as date math", rest.join(" ")));
+    }
+
+    Ok(Some(json!({
+        "kind": "date",
+        "expression": expression,
+        "result": date.format("%Y-%m-%d (%A)").to_string(),
+        "value": date.format("%Y-%m-%d").to_string(),
+    })))
+}
+
+/// Evaluates arithmetic, a unit conversion (`5 km to mi`) or date math (`today + 30 days`).
+/// Responds with `{ kind, expression, result, value }`, where `result` is ready to show.
+pub fn evaluate(expression: &str) -> Result<Value> {
+    evaluate_at(expression, Local::now().date_naive())
+}
+
+fn evaluate_at(expression: &str, today: NaiveDate) -> Result<Value> {
+    if expression.len() > MAX_EXPRESSION {
+        return Err(eyre!(
+            "Expressions can't be longer than {MAX_EXPRESSION} characters"
+        ));
+    }
+
+    let expression = expression.trim().to_lowercase();
+
+    if let Some(value) = date_math(&expression, today)? {
+        return Ok(value);
+    }
+
+    if let Some(value) = conversion(&expression)? {
+        return Ok(value);
+    }
+
+    let value = eval(&expression)?;
+
+    Ok(json!({
+        "kind": "arithmetic",
+        "expression": expression,
+        "result": format_number(value),
+        "value": value,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use architectury::prelude::*;
+    use chrono::NaiveDate;
+
+    use super::evaluate_at;
+
+    #[test]
+    fn evaluates_expressions() -> Result<()> {
+        let today = NaiveDate::from_ymd_opt(2023, 4, 12).unwrap();
+        let result = |expression: &str| -> Result<String> {
+            Ok(evaluate_at(expression, today)?["result"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string())
+        };
+
+        assert_eq!(result("0.1 + 0.2")?, "0.3");
+        assert_eq!(result("2^10 / 4")?, "256");
+        assert_eq!(result("26.2 miles to km")?, "42.1648128 km");
+        assert_eq!(result("350°F in c")?, "176.6666666667 °c");
+        assert_eq!(
+            result("today + 1 month - 2 days")?,
+            "2023-05-10 (Wednesday)"
+        );
+        assert_eq!(result("2023-12-25 - today")?, "257 days");
+        assert!(evaluate_at("5 kg to km", today).is_err());
+        assert!(evaluate_at("1 / 0", today).is_err());
+
+        Ok(())
+    }
+}
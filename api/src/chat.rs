@@ -3,17 +3,53 @@ use std::sync::Arc;
 
 use architectury::prelude::*;
 use futures::prelude::*;
+use futures::stream::BoxStream;
 use openchad_schemas::botconfig::BotConfig;
-use openchad_schemas::chat::{ChatMessage, ChatResponseStream};
-use serde_json::json;
-use tokio::io::AsyncBufReadExt;
+use openchad_schemas::chat::{
+    ChatContent, ChatError, ChatMessage, ChatResponseStream, ChatRole, ChatToolCallDelta,
+    FinishReason,
+};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
 use tokio_util::io::StreamReader;
 
+use crate::error::{self, ApiError};
+use crate::limits;
+use crate::usage::Call;
+
 const CHAT_CHUNKS: usize = 25;
+const MODEL: &str = "gpt-3.5-turbo";
+const VISION_MODEL: &str = "gpt-4o-mini";
+const CONTINUE_PROMPT: &str = "Continue exactly where you left off, without repeating anything.";
+
+#[derive(Debug, Default, Clone)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub arguments: String, // JSON object
+}
+
+/// What the model did with a turn of a `tools.*` task
+pub enum ToolTurn {
+    Calls(Vec<ToolCall>),
+    Answer(BoxStream<'static, Result<String, std::io::Error>>),
+}
 
+/// What the model sent back in an answer stream
+#[derive(Debug)]
+enum Event {
+    Text(String),
+    ToolCall(ChatToolCallDelta),
+    Finished(FinishReason),
+}
+
+/// Streams an answer to `message`, recording the call as `step` of the request being handled. An
+/// answer cut off at the length limit is continued up to `continueTruncated` times, after which
+/// the stream ends with `ApiError::Truncated`.
 pub async fn chat_request(
+    step: &str,
     header: &str,
-    message: String,
+    message: ChatContent,
     history: &[ChatMessage],
     config: Arc<BotConfig>,
 ) -> Result<impl Stream<Item = Result<String, std::io::Error>>> {
@@ -22,86 +58,357 @@ pub async fn chat_request(
             .get(history.len().saturating_sub(config.message_history)..)
             .unwrap_or_default(),
         &[
-            ChatMessage {
-                role: "system".into(),
-                content: header.into(),
-            },
-            ChatMessage {
-                role: "user".into(),
-                content: message,
-            },
+            ChatMessage::new(ChatRole::System, header),
+            ChatMessage::new(ChatRole::User, message),
         ],
     ]
     .concat();
 
-    let est_tokens = estimate_tokens(
-        &input
+    let model = match input.last().map(|m| &m.content) {
+        Some(ChatContent::Parts(_)) => var("OPENAI_VISION_MODEL").unwrap_or(VISION_MODEL.into()),
+        _ => MODEL.into(),
+    };
+
+    let step = step.to_string();
+    let continues = config.continue_truncated.unwrap_or_default();
+    let events = answer_events(&model, &step, &input).await?.boxed();
+    // the stream is read after the handler's scope is gone, so continuations are put back in it
+    let caller = limits::current();
+    let continuation = {
+        let step = step.clone();
+        move |input: Vec<ChatMessage>| {
+            let (model, step, caller) = (model.clone(), step.clone(), caller.clone());
+            async move {
+                let continuation = answer_events(&model, &step, &input);
+                Ok(match caller {
+                    Some(caller) => limits::scoped(caller, continuation).await?,
+                    None => continuation.await?,
+                }
+                .boxed())
+            }
+        }
+    };
+
+    Ok(continue_truncated(
+        step,
+        input,
+        continues,
+        events,
+        continuation,
+    ))
+}
+
+/// Passes the answer in `events` through, asking `continuation` for more up to `continues` times
+/// when it's cut off at the length limit
+fn continue_truncated<F, Fut>(
+    step: String,
+    mut input: Vec<ChatMessage>,
+    continues: usize,
+    mut events: BoxStream<'static, Result<Event, std::io::Error>>,
+    continuation: F,
+) -> impl Stream<Item = Result<String, std::io::Error>>
+where
+    F: Fn(Vec<ChatMessage>) -> Fut,
+    Fut: Future<Output = Result<BoxStream<'static, Result<Event, std::io::Error>>, ApiError>>,
+{
+    async_stream::try_stream! {
+        let mut continued = 0;
+        let mut answer = String::new();
+
+        while let Some(event) = events.next().await {
+            match event? {
+                Event::Text(text) => {
+                    answer.push_str(&text);
+                    yield text;
+                }
+                Event::Finished(FinishReason::Length) if continued < continues => {
+                    continued += 1;
+                    info!("<{step}> Cut off at the length limit, continuing ({continued}/{continues})");
+
+                    // the usage comes after the finish reason, and the call is only recorded with
+                    // it once its stream has been read to the end
+                    while let Some(rest) = events.next().await {
+                        if let Err(e) = rest {
+                            warn!("<{step}> The cut off answer ended badly: {e}");
+                        }
+                    }
+
+                    input.push(ChatMessage::new(ChatRole::Assistant, std::mem::take(&mut answer)));
+                    input.push(ChatMessage::new(ChatRole::User, CONTINUE_PROMPT));
+                    events = continuation(input.clone()).await?;
+                }
+                Event::Finished(FinishReason::Length) => Err::<(), _>(ApiError::Truncated)?,
+                Event::Finished(_) | Event::ToolCall(_) => {}
+            }
+        }
+    }
+}
+
+/// Starts an answer to `messages` from `model`, recorded as `step`
+async fn answer_events(
+    model: &str,
+    step: &str,
+    messages: &[ChatMessage],
+) -> Result<impl Stream<Item = Result<Event, std::io::Error>>, ApiError> {
+    let prompt = estimate_tokens(
+        &messages
             .iter()
-            .map(|m| &m.content)
-            .cloned()
+            .map(|m| m.content.text())
             .collect::<Vec<_>>()
             .join("\n"),
     );
+    warn!("Sending ~{prompt} tokens to {model}");
 
-    warn!(
-        "Sending {} tokens (~{}¢)",
-        est_tokens,
-        ((est_tokens as f64) / 10.0) * 0.002
-    );
+    let response = post(json!({
+        "model": model,
+        "messages": messages,
+        "stream": true,
+        "stream_options": { "include_usage": true }
+    }))
+    .await?;
+
+    Ok(events(reader(response), Call::new(model, step, prompt)))
+}
 
+/// Sends a chat completion request. An error status is an error, with OpenAI's message if the body
+/// has one
+async fn post(body: Value) -> Result<reqwest::Response, ApiError> {
     let response = reqwest::Client::new()
         .post("https://api.openai.com/v1/chat/completions")
-        .header(
-            "Authorization",
-            "Bearer ".to_owned() + &var("OPENAI_API_KEY").unwrap(),
-        )
-        .json(&json!({
-            "model": "gpt-3.5-turbo",
-            "messages": input,
-            "stream": true
-        }))
+        .header("Authorization", "Bearer ".to_owned() + &api_key()?)
+        .json(&body)
         .send()
-        .await?;
+        .await
+        .map_err(|e| ApiError::Llm(e.to_string()))?;
 
-    let stream = response
-        .bytes_stream()
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e));
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
 
-    let mut reader = StreamReader::new(stream);
+    let text = response.text().await.unwrap_or_default();
+    let message = match serde_json::from_str::<ChatError>(&text) {
+        Ok(error) => error.error.message,
+        Err(_) => text,
+    };
 
-    Ok(async_stream::try_stream! {
-        let mut acc = String::new();
+    Err(ApiError::Llm(format!("{status}: {message}")))
+}
+
+fn reader(response: reqwest::Response) -> impl AsyncBufRead + Unpin + Send + 'static {
+    StreamReader::new(response.bytes_stream().map_err(std::io::Error::other))
+}
+
+/// Reads an answer out of a completion stream, batching fragments and recording them to `call`.
+/// An error object, or the stream ending before `[DONE]`, is an `ApiError::Llm`.
+fn events<R: AsyncBufRead + Unpin + Send + 'static>(
+    mut reader: R,
+    mut call: Call,
+) -> impl Stream<Item = Result<Event, std::io::Error>> {
+    async_stream::try_stream! {
         let mut buf = vec![];
-        let mut i = 0;
+        let mut line = String::new();
+
         loop {
-            reader.read_line(&mut acc).await?;
+            line.clear();
+
+            let failure = if reader.read_line(&mut line).await? == 0 {
+                Some("The stream ended before the answer was done".to_string())
+            } else {
+                None
+            };
+
+            // blank lines separate events and lines starting with `:` are comments
+            let data = line.trim().strip_prefix("data:").map(str::trim);
 
-            if acc.trim() == "data: [DONE]" {
-                yield buf.join("");
+            if data == Some("[DONE]") {
                 break;
             }
 
-            if acc.starts_with("data: ") {
-                if let Ok(frag) = serde_json::from_str::<ChatResponseStream>(acc[6..].trim()) {
-                    if let Some(word) = frag.choices[0].delta.get("content") {
-                        i += 1;
-                        if i % CHAT_CHUNKS == 0 {
-                            buf.push(word.to_string());
-                            yield buf.join("");
-                            buf.clear();
-                        } else {
-                            buf.push(word.to_string());
-                        }
-                    }
+            let failure = failure.or_else(|| {
+                serde_json::from_str::<ChatError>(data?)
+                    .ok()
+                    .map(|error| error.error.message)
+            });
+
+            // what came before the error is still sent
+            if let Some(failure) = failure {
+                if !buf.is_empty() {
+                    yield Event::Text(buf.join(""));
+                }
+
+                Err::<(), _>(ApiError::Llm(failure))?;
+            }
+
+            let Some(data) = data else {
+                continue;
+            };
+
+            let frag = match serde_json::from_str::<ChatResponseStream>(data) {
+                Ok(frag) => frag,
+                Err(e) => {
+                    warn!("Skipping a fragment that doesn't parse ({e}): {data}");
+                    continue;
+                }
+            };
+
+            if let Some(usage) = frag.usage {
+                call.usage(usage);
+            }
+
+            // the usage chunk comes with no choices
+            let Some(choice) = frag.choices.into_iter().next() else {
+                continue;
+            };
+
+            if let Some(content) = choice.delta.content.filter(|c| !c.is_empty()) {
+                call.push(&content);
+                buf.push(content);
+
+                if buf.len() >= CHAT_CHUNKS {
+                    yield Event::Text(buf.join(""));
+                    buf.clear();
+                }
+            }
+
+            for tool_call in choice.delta.tool_calls.into_iter().flatten() {
+                let function = &tool_call.function;
+                for part in [&function.name, &function.arguments].into_iter().flatten() {
+                    call.push(part);
+                }
+
+                yield Event::ToolCall(tool_call);
+            }
+
+            if let Some(reason) = choice.finish_reason {
+                if !buf.is_empty() {
+                    yield Event::Text(buf.join(""));
+                    buf.clear();
+                }
+
+                yield Event::Finished(reason);
+            }
+        }
+
+        if !buf.is_empty() {
+            yield Event::Text(buf.join(""));
+        }
+    }
+}
+
+/// The whole answer, even if it was cut off at the length limit
+pub async fn collect(
+    stream: impl Stream<Item = Result<String, std::io::Error>>,
+) -> Result<String, std::io::Error> {
+    let mut stream = Box::pin(stream);
+    let mut answer = String::new();
+
+    while let Some(part) = stream.next().await {
+        match part {
+            Ok(part) => answer.push_str(&part),
+            Err(e) if error::truncated(&e) => {
+                warn!("Going on with an answer that was cut off");
+                break;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(answer)
+}
+
+/// Sends `messages` along with function definitions. Tool call deltas are collected until the
+/// model is done, while an answer is streamed straight through. `final_turn` forbids tool calls.
+pub async fn tool_request(
+    step: &str,
+    messages: &[Value],
+    tools: &[Value],
+    final_turn: bool,
+) -> Result<ToolTurn> {
+    let prompt = estimate_tokens(
+        &messages
+            .iter()
+            .filter_map(|m| m["content"].as_str())
+            .collect::<Vec<_>>()
+            .join("\n"),
+    );
+    warn!("Sending ~{prompt} tokens to {MODEL}");
+
+    let response = post(json!({
+        "model": MODEL,
+        "messages": messages,
+        "tools": tools,
+        "tool_choice": if final_turn { "none" } else { "auto" },
+        "stream": true,
+        "stream_options": { "include_usage": true }
+    }))
+    .await?;
+
+    let events = events(reader(response), Call::new(MODEL, step, prompt));
+
+    Ok(tool_turn(events.boxed()).await?)
+}
+
+/// Collects the tool calls of a turn, or hands the stream over as the answer once text comes in
+async fn tool_turn(
+    mut events: BoxStream<'static, Result<Event, std::io::Error>>,
+) -> Result<ToolTurn, ApiError> {
+    let mut calls: Vec<ToolCall> = vec![];
+
+    while let Some(event) = events.next().await {
+        match event? {
+            Event::Text(text) if calls.is_empty() => {
+                return Ok(ToolTurn::Answer(answer_stream(text, events).boxed()));
+            }
+            Event::ToolCall(delta) => {
+                if calls.len() <= delta.index {
+                    calls.resize_with(delta.index + 1, ToolCall::default);
                 }
+
+                let acc = &mut calls[delta.index];
+
+                if let Some(id) = delta.id {
+                    acc.id = id;
+                }
+                acc.name += delta.function.name.as_deref().unwrap_or_default();
+                acc.arguments += delta.function.arguments.as_deref().unwrap_or_default();
+            }
+            // the arguments were cut off
+            Event::Finished(FinishReason::Length) if !calls.is_empty() => {
+                return Err(ApiError::Truncated);
             }
+            Event::Text(_) | Event::Finished(_) => {}
+        }
+    }
+
+    if calls.is_empty() {
+        Ok(ToolTurn::Answer(stream::empty().boxed()))
+    } else {
+        Ok(ToolTurn::Calls(calls))
+    }
+}
 
-            acc.clear();
+/// The rest of an answer whose first text has already been read
+fn answer_stream(
+    first: String,
+    events: impl Stream<Item = Result<Event, std::io::Error>>,
+) -> impl Stream<Item = Result<String, std::io::Error>> {
+    let rest = events.try_filter_map(|event| async move {
+        match event {
+            Event::Text(text) => Ok(Some(text)),
+            Event::Finished(FinishReason::Length) => Err(ApiError::Truncated.into()),
+            Event::Finished(_) | Event::ToolCall(_) => Ok(None),
         }
-    })
+    });
+
+    stream::once(future::ready(Ok(first))).chain(rest)
 }
 
-fn estimate_tokens(s: &str) -> usize {
+fn api_key() -> Result<String, ApiError> {
+    var("OPENAI_API_KEY").map_err(|_| ApiError::BadConfig("OPENAI_API_KEY isn't set".into()))
+}
+
+pub fn estimate_tokens(s: &str) -> usize {
     ((s.len() as f32
         / ((s
             .split_ascii_whitespace()
@@ -111,3 +418,166 @@ fn estimate_tokens(s: &str) -> usize {
         * 1.5)
         .ceil() as usize
 }
+
+#[cfg(test)]
+mod tests {
+    use architectury::prelude::*;
+    use futures::prelude::*;
+    use openchad_schemas::chat::FinishReason;
+
+    use sqlx::SqlitePool;
+
+    use super::{collect, continue_truncated, events, tool_turn, Event, ToolTurn, MODEL};
+    use crate::error::ApiError;
+    use crate::limits::{self, Spending};
+    use crate::usage::Call;
+
+    const ANSWER: &str = r#"data: {"id":"chatcmpl-1","object":"chat.completion.chunk","created":1700000000,"model":"gpt-3.5-turbo-0125","choices":[{"index":0,"delta":{"role":"assistant","content":"","refusal":null},"finish_reason":null}],"usage":null}
+
+: keep-alive
+data: {"id":"chatcmpl-1","object":"chat.completion.chunk","created":1700000000,"model":"gpt-3.5-turbo-0125","choices":[{"index":0,"delta":{"content":"Hello"},"finish_reason":null}],"usage":null}
+
+data: {"truncated json
+
+data: {"id":"chatcmpl-1","object":"chat.completion.chunk","created":1700000000,"model":"gpt-3.5-turbo-0125","choices":[{"index":0,"delta":{"content":" there"},"finish_reason":null}],"usage":null}
+
+data: {"id":"chatcmpl-1","object":"chat.completion.chunk","created":1700000000,"model":"gpt-3.5-turbo-0125","choices":[{"index":0,"delta":{},"finish_reason":"length"}],"usage":null}
+
+data: {"id":"chatcmpl-1","object":"chat.completion.chunk","created":1700000000,"model":"gpt-3.5-turbo-0125","choices":[],"usage":{"prompt_tokens":12,"completion_tokens":2,"total_tokens":14}}
+
+data: [DONE]
+
+"#;
+
+    const ERROR: &str = r#"data: {"id":"chatcmpl-2","object":"chat.completion.chunk","created":1700000000,"model":"gpt-3.5-turbo-0125","choices":[{"index":0,"delta":{"content":"Hi"},"finish_reason":null}],"usage":null}
+
+data: {"error":{"message":"The server had an error while processing your request.","type":"server_error","param":null,"code":null}}
+
+"#;
+
+    const CLOSED: &str = r#"data: {"id":"chatcmpl-3","object":"chat.completion.chunk","created":1700000000,"model":"gpt-3.5-turbo-0125","choices":[{"index":0,"delta":{"content":"Hi"},"finish_reason":null}],"usage":null}
+
+"#;
+
+    const TOOL_CALL: &str = r#"data: {"id":"chatcmpl-4","object":"chat.completion.chunk","created":1700000000,"model":"gpt-3.5-turbo-0125","choices":[{"index":0,"delta":{"role":"assistant","content":null,"tool_calls":[{"index":0,"id":"call_1","type":"function","function":{"name":"searchNews","arguments":""}}],"refusal":null},"finish_reason":null}],"usage":null}
+
+data: {"id":"chatcmpl-4","object":"chat.completion.chunk","created":1700000000,"model":"gpt-3.5-turbo-0125","choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":"{\"input\":"}}]},"finish_reason":null}],"usage":null}
+
+data: {"id":"chatcmpl-4","object":"chat.completion.chunk","created":1700000000,"model":"gpt-3.5-turbo-0125","choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":"\"volcano\"}"}}]},"finish_reason":null}],"usage":null}
+
+data: {"id":"chatcmpl-4","object":"chat.completion.chunk","created":1700000000,"model":"gpt-3.5-turbo-0125","choices":[{"index":0,"delta":{},"finish_reason":"tool_calls"}],"usage":null}
+
+data: {"id":"chatcmpl-4","object":"chat.completion.chunk","created":1700000000,"model":"gpt-3.5-turbo-0125","choices":[],"usage":{"prompt_tokens":80,"completion_tokens":18,"total_tokens":98}}
+
+data: [DONE]
+
+"#;
+
+    const TOOL_ANSWER: &str = r#"data: {"id":"chatcmpl-5","object":"chat.completion.chunk","created":1700000000,"model":"gpt-3.5-turbo-0125","choices":[{"index":0,"delta":{"role":"assistant","content":"It erupted","refusal":null},"finish_reason":null}],"usage":null}
+
+data: {"id":"chatcmpl-5","object":"chat.completion.chunk","created":1700000000,"model":"gpt-3.5-turbo-0125","choices":[{"index":0,"delta":{"content":" overnight."},"finish_reason":"length"}],"usage":null}
+
+data: [DONE]
+
+"#;
+
+    const CONTINUED: &str = r#"data: {"id":"chatcmpl-6","object":"chat.completion.chunk","created":1700000000,"model":"gpt-3.5-turbo-0125","choices":[{"index":0,"delta":{"role":"assistant","content":", friend.","refusal":null},"finish_reason":null}],"usage":null}
+
+data: {"id":"chatcmpl-6","object":"chat.completion.chunk","created":1700000000,"model":"gpt-3.5-turbo-0125","choices":[{"index":0,"delta":{},"finish_reason":"stop"}],"usage":null}
+
+data: {"id":"chatcmpl-6","object":"chat.completion.chunk","created":1700000000,"model":"gpt-3.5-turbo-0125","choices":[],"usage":{"prompt_tokens":20,"completion_tokens":3,"total_tokens":23}}
+
+data: [DONE]
+
+"#;
+
+    async fn turn(transcript: &'static str) -> Result<ToolTurn, ApiError> {
+        tool_turn(events(transcript.as_bytes(), Call::new(MODEL, "test", 0)).boxed()).await
+    }
+
+    async fn read(transcript: &'static str) -> Vec<Result<Event, ApiError>> {
+        events(transcript.as_bytes(), Call::new(MODEL, "test", 0))
+            .map_err(ApiError::from)
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn reads_recorded_streams() -> Result<()> {
+        let answer = read(ANSWER).await;
+        assert!(matches!(
+            &answer[..],
+            [Ok(Event::Text(text)), Ok(Event::Finished(FinishReason::Length))]
+                if text == "Hello there"
+        ));
+
+        let error = read(ERROR).await;
+        assert!(matches!(
+            &error[..],
+            [Ok(Event::Text(_)), Err(ApiError::Llm(message))]
+                if message.starts_with("The server had an error")
+        ));
+
+        let closed = read(CLOSED).await;
+        assert!(matches!(closed.last(), Some(Err(ApiError::Llm(_)))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn continues_truncated_answers_with_their_usage() -> Result<()> {
+        let caller = Spending::unlimited(SqlitePool::connect_lazy("sqlite::memory:")?);
+
+        let answer = limits::scoped(caller.clone(), async {
+            let first = events(ANSWER.as_bytes(), Call::new(MODEL, "test", 0)).boxed();
+            let continuation = |input: Vec<_>| async move {
+                assert_eq!(input.len(), 2);
+                Ok(events(CONTINUED.as_bytes(), Call::new(MODEL, "test", 0)).boxed())
+            };
+
+            collect(continue_truncated(
+                "test".into(),
+                vec![],
+                1,
+                first,
+                continuation,
+            ))
+            .await
+        })
+        .await?;
+
+        assert_eq!(answer, "Hello there, friend.");
+        // both calls are counted with the tokens the API reported, not estimates
+        assert_eq!(caller.tokens(), 14 + 23);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reads_recorded_tool_turns() -> Result<()> {
+        let Ok(ToolTurn::Calls(calls)) = turn(TOOL_CALL).await else {
+            panic!("expected a tool call");
+        };
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].id, "call_1");
+        assert_eq!(calls[0].name, "searchNews");
+        assert_eq!(calls[0].arguments, r#"{"input":"volcano"}"#);
+
+        // the finish reason comes with the last of the text
+        let Ok(ToolTurn::Answer(answer)) = turn(TOOL_ANSWER).await else {
+            panic!("expected an answer");
+        };
+        let parts = answer.map_err(ApiError::from).collect::<Vec<_>>().await;
+        assert!(matches!(
+            &parts[..],
+            [Ok(text), Err(ApiError::Truncated)] if text == "It erupted overnight."
+        ));
+
+        let Ok(ToolTurn::Answer(answer)) = turn(TOOL_ANSWER).await else {
+            panic!("expected an answer");
+        };
+        assert_eq!(collect(answer).await?, "It erupted overnight.");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,143 @@
+use architectury::log::Report;
+use architectury::prelude::*;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use openchad_schemas::ErrorBody;
+
+/// Why a request failed. Errors are passed around as `eyre` reports, with one of these inside
+/// wherever the cause is known, and anything else is an internal error
+#[derive(Debug, thiserror::Error)]
+pub enum ApiError {
+    #[error("Bad config: {0}")]
+    BadConfig(String),
+    #[error("The language model failed: {0}")]
+    Llm(String),
+    /// Only ever ends a stream, after the answer as far as it got
+    #[error("The answer was cut off at the length limit")]
+    Truncated,
+    #[error("A provider failed: {0}")]
+    Provider(String),
+    #[error("A template failed to render: {0}")]
+    Template(String),
+    #[error("{message}")]
+    RateLimited { message: String, retry_after: u64 }, // Seconds
+    #[error("{0} wasn't found")]
+    NotFound(String),
+    #[error("{0}")]
+    Unauthorized(String),
+    #[error("{0:#}")]
+    Internal(Report),
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::BadConfig(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::Llm(_) | Self::Truncated | Self::Provider(_) => StatusCode::BAD_GATEWAY,
+            Self::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Self::Template(_) | Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn body(&self) -> ErrorBody {
+        let kind = match self {
+            Self::BadConfig(_) => "badConfig",
+            Self::Llm(_) => "llm",
+            Self::Truncated => "truncated",
+            Self::Provider(_) => "provider",
+            Self::Template(_) => "template",
+            Self::RateLimited { .. } => "rateLimited",
+            Self::NotFound(_) => "notFound",
+            Self::Unauthorized(_) => "unauthorized",
+            Self::Internal(_) => "internal",
+        };
+
+        ErrorBody {
+            kind: kind.into(),
+            message: self.to_string(),
+        }
+    }
+}
+
+/// Whether a stream ended because the answer hit the length limit, rather than failing
+pub fn truncated(error: &std::io::Error) -> bool {
+    matches!(
+        error.get_ref().and_then(|e| e.downcast_ref::<ApiError>()),
+        Some(ApiError::Truncated)
+    )
+}
+
+impl From<Report> for ApiError {
+    fn from(report: Report) -> Self {
+        report.downcast::<Self>().unwrap_or_else(Self::Internal)
+    }
+}
+
+// streams can only fail with an `io::Error`, so an `ApiError` is carried inside one
+impl From<std::io::Error> for ApiError {
+    fn from(error: std::io::Error) -> Self {
+        match error.get_ref().and_then(|e| e.downcast_ref::<Self>()) {
+            Some(_) => *error.into_inner().unwrap().downcast::<Self>().unwrap(),
+            None => Self::Internal(error.into()),
+        }
+    }
+}
+
+impl From<ApiError> for std::io::Error {
+    fn from(error: ApiError) -> Self {
+        std::io::Error::other(error)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+
+        if status.is_server_error() {
+            error!("{self}");
+        }
+
+        let retry_after = match &self {
+            Self::RateLimited { retry_after, .. } => Some(retry_after.to_string()),
+            _ => None,
+        };
+        let mut response = (status, Json(self.body())).into_response();
+
+        if let Some(retry_after) = retry_after.and_then(|r| r.parse().ok()) {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, retry_after);
+        }
+
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use architectury::log::Report;
+    use architectury::prelude::*;
+    use axum::http::StatusCode;
+    use eyre::eyre;
+
+    use super::ApiError;
+
+    #[test]
+    fn errors_survive_reports_and_streams() -> Result<()> {
+        let report: Report = ApiError::Provider("timed out".into()).into();
+        let error = ApiError::from(report);
+        assert_eq!(error.status(), StatusCode::BAD_GATEWAY);
+
+        let error = ApiError::from(std::io::Error::from(error));
+        assert_eq!(error.body().kind, "provider");
+
+        let error = ApiError::from(eyre!("disk full"));
+        assert_eq!(error.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(error.body().message, "disk full");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,378 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use architectury::prelude::*;
+use chrono::{DateTime, FixedOffset, Local};
+use eyre::Context;
+use futures::future::join_all;
+use once_cell::sync::Lazy;
+use roxmltree::{Document, Node};
+use serde_json::{json, Value};
+use tokio::sync::Mutex;
+
+use crate::localsearch::tokenize;
+
+const DEFAULT_REFRESH: u64 = 900;
+const DEFAULT_MAX_AGE_DAYS: u64 = 7;
+const DEFAULT_COUNT: usize = 3;
+const SNIPPET_CHARS: usize = 400;
+
+/// When each feed URL was last fetched, and the entries it had then
+type FeedCache = HashMap<String, (Instant, Vec<FeedEntry>)>;
+
+/// Fetched entries for each feed URL, reused until they're older than the provider's `refresh`
+static FEEDS: Lazy<Mutex<FeedCache>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone)]
+pub struct FeedEntry {
+    pub feed: Option<String>,
+    pub title: Option<String>,
+    pub link: Option<String>,
+    pub summary: Option<String>,
+    pub published: Option<DateTime<FixedOffset>>,
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, names: &[&str]) -> Option<Node<'a, 'input>> {
+    names.iter().find_map(|name| {
+        node.children()
+            .find(|c| c.is_element() && c.tag_name().name() == *name)
+    })
+}
+
+fn child_text(node: Node, names: &[&str]) -> Option<String> {
+    child(node, names).map(|c| c.text().unwrap_or_default().trim().to_string())
+}
+
+fn parse_date(date: &str) -> Option<DateTime<FixedOffset>> {
+    DateTime::parse_from_rfc2822(date)
+        .or_else(|_| DateTime::parse_from_rfc3339(date))
+        .ok()
+}
+
+/// Feed summaries are usually HTML, which isn't useful in a prompt
+fn strip_tags(source: &str) -> String {
+    let mut in_tag = false;
+    let text = source
+        .chars()
+        .filter(|c| match c {
+            '<' => {
+                in_tag = true;
+                false
+            }
+            '>' => {
+                in_tag = false;
+                false
+            }
+            _ => !in_tag,
+        })
+        .collect::<String>();
+
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Reads the entries of an RSS 1.0/2.0 or Atom feed
+pub fn parse_feed(doc: &Document) -> (Option<String>, Vec<FeedEntry>) {
+    let root = doc.root_element();
+    let title = child_text(child(root, &["channel"]).unwrap_or(root), &["title"]);
+
+    let entries = root
+        .descendants()
+        .filter(|n| n.is_element() && matches!(n.tag_name().name(), "item" | "entry"))
+        .map(|item| FeedEntry {
+            feed: title.clone(),
+            title: child_text(item, &["title"]),
+            link: child(item, &["link"]).map(|l| {
+                l.attribute("href")
+                    .or(l.text())
+                    .unwrap_or_default()
+                    .trim()
+                    .to_string()
+            }),
+            summary: child_text(item, &["description", "summary", "content"])
+                .map(|s| strip_tags(&s)),
+            published: child_text(item, &["pubDate", "published", "updated", "date"])
+                .and_then(|date| parse_date(&date)),
+        })
+        .collect();
+
+    (title, entries)
+}
+
+/// Normalizes a feed into `{ title, items: [{ title, link, summary, published }] }`
+pub fn feed_to_value(doc: &Document) -> Value {
+    let (title, entries) = parse_feed(doc);
+
+    json!({
+        "title": title,
+        "items": entries.iter().map(|entry| json!({
+            "title": entry.title,
+            "link": entry.link,
+            "summary": entry.summary,
+            "published": entry.published.map(|date| date.to_rfc3339()),
+        })).collect::<Vec<_>>(),
+    })
+}
+
+async fn fetch_feed(url: &str) -> Result<Vec<FeedEntry>> {
+    let text = reqwest::Client::new()
+        .get(url)
+        .timeout(Duration::from_secs(10))
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+
+    let doc = Document::parse(&text).context("Invalid RSS/Atom feed")?;
+
+    Ok(parse_feed(&doc).1)
+}
+
+/// Entries from every feed, refetching the ones that are older than `refresh` seconds.
+/// Feeds that fail to refetch keep serving their stale entries.
+async fn entries(urls: &[String], refresh: u64) -> Vec<FeedEntry> {
+    let stale = {
+        let feeds = FEEDS.lock().await;
+        urls.iter()
+            .filter(|url| {
+                feeds
+                    .get(*url)
+                    .is_none_or(|(fetched, _)| fetched.elapsed() >= Duration::from_secs(refresh))
+            })
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+
+    let fetched = join_all(stale.iter().map(|url| async move {
+        info!("Fetching feed {url:?}");
+        (url, fetch_feed(url).await)
+    }))
+    .await;
+
+    let mut feeds = FEEDS.lock().await;
+
+    for (url, result) in fetched {
+        match result {
+            Ok(entries) => {
+                feeds.insert(url.clone(), (Instant::now(), entries));
+            }
+            Err(e) => warn!("Failed to fetch feed {url:?}: {e}"),
+        }
+    }
+
+    urls.iter()
+        .filter_map(|url| feeds.get(url))
+        .flat_map(|(_, entries)| entries.iter().cloned())
+        .collect()
+}
+
+/// Ranks recent entries against the `q` prop, shaped like a Bing web search response
+pub async fn search_response(
+    urls: &[String],
+    refresh: Option<u64>,
+    max_age_days: Option<u64>,
+    props: &HashMap<String, String>,
+) -> Value {
+    let entries = entries(urls, refresh.unwrap_or(DEFAULT_REFRESH)).await;
+
+    rank(entries, max_age_days, props, Local::now())
+}
+
+/// Drops entries older than `max_age_days` and keeps the `count` best matches for `q`
+fn rank(
+    entries: Vec<FeedEntry>,
+    max_age_days: Option<u64>,
+    props: &HashMap<String, String>,
+    now: DateTime<Local>,
+) -> Value {
+    let query = props
+        .get("q")
+        .map(|q| tokenize(q).collect::<HashSet<_>>())
+        .unwrap_or_default();
+    let count = props
+        .get("count")
+        .and_then(|count| count.trim().parse().ok())
+        .unwrap_or(DEFAULT_COUNT);
+    let max_age = chrono::Duration::days(max_age_days.unwrap_or(DEFAULT_MAX_AGE_DAYS) as i64);
+
+    let entries = entries
+        .into_iter()
+        .filter(|entry| {
+            entry
+                .published
+                .is_none_or(|published| now.signed_duration_since(published) <= max_age)
+        })
+        .map(|entry| {
+            let terms = tokenize(entry.title.as_deref().unwrap_or_default())
+                .chain(tokenize(entry.summary.as_deref().unwrap_or_default()))
+                .collect::<HashSet<_>>();
+            (entry, terms)
+        })
+        .collect::<Vec<_>>();
+
+    // rarer terms say more about relevance, then newer entries win
+    let n = entries.len() as f64;
+    let mut scored = entries
+        .iter()
+        .map(|(entry, terms)| {
+            let relevance = query
+                .iter()
+                .filter(|term| terms.contains(*term))
+                .map(|term| {
+                    let df = entries.iter().filter(|(_, t)| t.contains(term)).count() as f64;
+                    (n / df).ln() + 1.0
+                })
+                .sum::<f64>();
+
+            let age_days = entry
+                .published
+                .map_or(max_age.num_days() as f64, |published| {
+                    now.signed_duration_since(published).num_hours().max(0) as f64 / 24.0
+                });
+
+            (entry, relevance / (1.0 + age_days / 7.0), relevance)
+        })
+        .filter(|(_, _, relevance)| query.is_empty() || *relevance > 0.0)
+        .collect::<Vec<_>>();
+
+    scored.sort_by(|(a, a_score, _), (b, b_score, _)| {
+        b_score
+            .total_cmp(a_score)
+            .then_with(|| b.published.cmp(&a.published))
+    });
+
+    let value = scored
+        .into_iter()
+        .take(count)
+        .map(|(entry, _, _)| {
+            let snippet = entry.summary.clone().unwrap_or_default();
+            let snippet = match snippet.char_indices().nth(SNIPPET_CHARS) {
+                Some((i, _)) => format!("{}...", &snippet[..i]),
+                None => snippet,
+            };
+
+            json!({
+                "name": entry.title,
+                "url": entry.link,
+                "snippet": snippet,
+                "datePublished": entry.published.map(|date| date.to_rfc3339()),
+                "provider": entry.feed,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    json!({
+        "_type": "SearchResponse",
+        "webPages": {
+            "value": value
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use architectury::prelude::*;
+    use chrono::{DateTime, Local};
+    use roxmltree::Document;
+    use serde_json::json;
+
+    use super::{parse_feed, rank};
+
+    const RSS: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0" xmlns:atom="http://www.w3.org/2005/Atom">
+<channel>
+    <title><![CDATA[World News]]></title>
+    <link>https://news.example.com/world</link>
+    <item>
+        <title><![CDATA[Volcano erupts near Reykjavik]]></title>
+        <description><![CDATA[<p>Lava reached the outskirts of <b>Grindavik</b> overnight.</p>]]></description>
+        <link>https://news.example.com/world/volcano</link>
+        <pubDate>Mon, 12 Oct 2026 08:15:00 GMT</pubDate>
+    </item>
+    <item>
+        <title><![CDATA[Markets close higher]]></title>
+        <description><![CDATA[Stocks rallied for a third day.]]></description>
+        <link>https://news.example.com/world/markets</link>
+        <pubDate>Tue, 13 Oct 2026 17:40:00 GMT</pubDate>
+    </item>
+    <item>
+        <title><![CDATA[Volcano season begins]]></title>
+        <description><![CDATA[Scientists expect more eruptions this year.]]></description>
+        <link>https://news.example.com/world/season</link>
+        <pubDate>Thu, 01 Jan 2026 09:00:00 GMT</pubDate>
+    </item>
+</channel>
+</rss>
+"#;
+
+    const ATOM: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+    <title>Tech</title>
+    <id>https://tech.example.com/</id>
+    <updated>2026-10-13T12:00:00Z</updated>
+    <entry>
+        <title>New volcano sensors ship</title>
+        <link rel="alternate" href="https://tech.example.com/sensors"/>
+        <id>https://tech.example.com/sensors</id>
+        <published>2026-10-13T12:00:00Z</published>
+        <summary type="html">&lt;p&gt;Cheap seismometers for every volcano.&lt;/p&gt;</summary>
+    </entry>
+</feed>
+"#;
+
+    #[test]
+    fn parses_and_ranks_recorded_feeds() -> Result<()> {
+        let rss = Document::parse(RSS)?;
+        let atom = Document::parse(ATOM)?;
+        let (rss_title, rss_entries) = parse_feed(&rss);
+        let (atom_title, atom_entries) = parse_feed(&atom);
+
+        assert_eq!(rss_title.as_deref(), Some("World News"));
+        assert_eq!(atom_title.as_deref(), Some("Tech"));
+        assert_eq!(rss_entries.len(), 3);
+        assert_eq!(
+            rss_entries[0].summary.as_deref(),
+            Some("Lava reached the outskirts of Grindavik overnight.")
+        );
+        assert_eq!(
+            atom_entries[0].link.as_deref(),
+            Some("https://tech.example.com/sensors")
+        );
+
+        let now = DateTime::parse_from_rfc3339("2026-10-14T00:00:00Z")?.with_timezone(&Local);
+        let entries = rss_entries
+            .into_iter()
+            .chain(atom_entries)
+            .collect::<Vec<_>>();
+        let props = HashMap::from([("q".into(), "volcano".into()), ("count".into(), "5".into())]);
+        let response = rank(entries.clone(), None, &props, now);
+        let urls = response["webPages"]["value"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|result| result["url"].clone())
+            .collect::<Vec<_>>();
+
+        // the January entry is past the default max age and markets doesn't match
+        assert_eq!(
+            urls,
+            [
+                json!("https://tech.example.com/sensors"),
+                json!("https://news.example.com/world/volcano")
+            ]
+        );
+
+        let props = HashMap::from([("count".into(), "1".into())]);
+        let response = rank(entries, None, &props, now);
+
+        assert_eq!(
+            response["webPages"]["value"][0]["name"],
+            "Markets close higher"
+        );
+        assert_eq!(response["webPages"]["value"].as_array().unwrap().len(), 1);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,279 @@
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use architectury::prelude::*;
+use chrono::{Duration, Utc};
+use eyre::Context;
+use once_cell::sync::Lazy;
+use openchad_schemas::botconfig::{BotConfig, ConfigLimit};
+use sqlx::{Row, SqlitePool};
+
+use crate::error::ApiError;
+
+tokio::task_local! {
+    static CALLER: Caller;
+}
+
+// buckets are read, refilled and written back, which shouldn't interleave
+static BUCKETS: Lazy<tokio::sync::Mutex<()>> = Lazy::new(|| tokio::sync::Mutex::new(()));
+
+pub type Caller = Arc<Spending>;
+
+/// Who a request is for and what it has spent, which is added to the quotas of everyone it counts
+/// against once it's done
+pub struct Spending {
+    pub pool: SqlitePool,
+    pub user: String,
+    pub user_id: Option<String>,
+    pub guild: Option<String>,
+    pub endpoint: String,
+    keys: Vec<String>,
+    spent: Mutex<(u64, f64)>, // Tokens, dollars
+}
+
+impl Spending {
+    /// Counts `tokens` costing `cost` dollars against the request
+    pub fn spend(&self, tokens: usize, cost: f64) {
+        let mut spent = self.spent.lock().unwrap();
+        spent.0 += tokens as u64;
+        spent.1 += cost;
+    }
+}
+
+#[cfg(test)]
+impl Spending {
+    /// A request with no limits, to see what gets spent against it
+    pub fn unlimited(pool: SqlitePool) -> Caller {
+        Arc::new(Self {
+            pool,
+            user: "test".into(),
+            user_id: None,
+            guild: None,
+            endpoint: "test".into(),
+            keys: vec![],
+            spent: Mutex::new((0, 0.0)),
+        })
+    }
+
+    pub fn tokens(&self) -> u64 {
+        self.spent.lock().unwrap().0
+    }
+}
+
+impl Drop for Spending {
+    fn drop(&mut self) {
+        let (tokens, cost) = *self.spent.lock().unwrap();
+        if tokens == 0 {
+            return;
+        }
+
+        let pool = self.pool.clone();
+        let keys = std::mem::take(&mut self.keys);
+        let day = today();
+
+        tokio::spawn(async move {
+            for key in keys {
+                let result = sqlx::query(include_str!("../sql/QuotaAdd.sql"))
+                    .bind(&key)
+                    .bind(&day)
+                    .bind(tokens as i64)
+                    .bind(cost)
+                    .execute(&pool)
+                    .await;
+
+                if let Err(e) = result {
+                    warn!("Failed to add to the quota of {key}: {e}");
+                }
+            }
+        });
+    }
+}
+
+/// The request being handled, if there is one
+pub fn current() -> Option<Caller> {
+    CALLER.try_with(Arc::clone).ok()
+}
+
+/// Runs `future` with `caller` as the request to spend against. LLM calls made in it hold on to
+/// the caller, so answers streamed after the handler returns are still counted
+pub async fn scoped<F: Future>(caller: Caller, future: F) -> F::Output {
+    CALLER.scope(caller, future).await
+}
+
+fn limits<'a>(
+    config: &'a BotConfig,
+    user: &str,
+    guild: Option<&str>,
+) -> Vec<(String, Option<&'a ConfigLimit>)> {
+    let limits = config.limits.as_ref();
+    let mut keyed = vec![(format!("user:{user}"), limits.and_then(|l| l.user.as_ref()))];
+
+    if let Some(guild) = guild {
+        keyed.push((
+            format!("guild:{guild}"),
+            limits.and_then(|l| l.guild.as_ref()),
+        ));
+    }
+
+    keyed
+}
+
+fn today() -> String {
+    Utc::now().date_naive().to_string()
+}
+
+fn now() -> f64 {
+    Utc::now().timestamp_millis() as f64 / 1000.0
+}
+
+/// How full a bucket is `elapsed` seconds after it held `tokens`
+fn refill(tokens: f64, elapsed: f64, per_minute: f64, burst: f64) -> f64 {
+    (tokens + elapsed.max(0.0) / 60.0 * per_minute).min(burst)
+}
+
+fn too_many(key: &str, what: &str, minutes: f64) -> ApiError {
+    let minutes = minutes.ceil().max(1.0) as u64;
+    let whose = if key.starts_with("guild:") {
+        " for this server"
+    } else {
+        ""
+    };
+
+    info!("{key} is over its {what}, retry in {minutes}m");
+
+    ApiError::RateLimited {
+        message: format!(
+            "You've reached the {what}{whose}, you can ask again in {minutes} minute{}.",
+            if minutes == 1 { "" } else { "s" }
+        ),
+        retry_after: minutes * 60,
+    }
+}
+
+/// Checks `user`'s and `guild`'s daily quotas and rate limits. With `take`, the request is also
+/// taken out of their buckets, so only the request that does the work should take
+pub async fn admit(
+    pool: &SqlitePool,
+    config: &BotConfig,
+    user: &str,
+    user_id: Option<&str>,
+    guild: Option<&str>,
+    endpoint: &str,
+    take: bool,
+) -> Result<Caller, ApiError> {
+    let keyed = limits(config, user, guild);
+
+    // quotas only grow once a request is done, so they're read without holding the buckets
+    for (key, limit) in &keyed {
+        let Some(limit) = limit else {
+            continue;
+        };
+
+        if limit.daily_tokens.is_some() || limit.daily_cost.is_some() {
+            let spent = sqlx::query(include_str!("../sql/QuotaSelect.sql"))
+                .bind(key)
+                .bind(today())
+                .fetch_optional(pool)
+                .await
+                .context("Failed to query quotas")?
+                .map_or((0, 0.0), |row| {
+                    (row.get::<i64, _>("tokens") as u64, row.get("cost"))
+                });
+
+            if limit.daily_tokens.is_some_and(|max| spent.0 >= max)
+                || limit.daily_cost.is_some_and(|max| spent.1 >= max)
+            {
+                let midnight = (Utc::now() + Duration::days(1))
+                    .date_naive()
+                    .and_hms_opt(0, 0, 0)
+                    .unwrap();
+                let minutes = (midnight - Utc::now().naive_utc()).num_seconds() as f64 / 60.0;
+
+                return Err(too_many(key, "daily limit", minutes));
+            }
+        }
+    }
+
+    let rated = keyed
+        .iter()
+        .filter_map(|(key, limit)| Some((key, limit.and_then(|l| l.per_minute)?, (*limit)?)))
+        .collect::<Vec<_>>();
+    let _buckets = if rated.is_empty() {
+        None
+    } else {
+        Some(BUCKETS.lock().await)
+    };
+    let mut taken = vec![];
+
+    for (key, per_minute, limit) in rated {
+        let burst = limit.burst.unwrap_or(1.0);
+        let now = now();
+        let tokens = sqlx::query(include_str!("../sql/RateLimitSelect.sql"))
+            .bind(key)
+            .fetch_optional(pool)
+            .await
+            .context("Failed to query rate limits")?
+            .map_or(burst, |row| {
+                refill(
+                    row.get("tokens"),
+                    now - row.get::<f64, _>("updated"),
+                    per_minute,
+                    burst,
+                )
+            });
+
+        if tokens < 1.0 {
+            return Err(too_many(key, "rate limit", (1.0 - tokens) / per_minute));
+        }
+
+        taken.push((key, tokens - 1.0, now));
+    }
+
+    if take {
+        for (key, tokens, updated) in taken {
+            sqlx::query(include_str!("../sql/RateLimitUpsert.sql"))
+                .bind(key)
+                .bind(tokens)
+                .bind(updated)
+                .execute(pool)
+                .await
+                .context("Failed to update rate limits")?;
+        }
+    }
+
+    Ok(Arc::new(Spending {
+        pool: pool.clone(),
+        user: user.into(),
+        user_id: user_id.map(String::from),
+        guild: guild.map(String::from),
+        endpoint: endpoint.into(),
+        keys: keyed.into_iter().map(|(key, _)| key).collect(),
+        spent: Mutex::new((0, 0.0)),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use architectury::prelude::*;
+    use axum::http::StatusCode;
+
+    use super::{refill, too_many};
+    use crate::error::ApiError;
+
+    #[test]
+    fn bucket_refills_up_to_burst() -> Result<()> {
+        assert_eq!(refill(0.0, 30.0, 2.0, 3.0), 1.0);
+        assert_eq!(refill(0.5, 600.0, 2.0, 3.0), 3.0);
+        assert_eq!(refill(1.0, -5.0, 2.0, 3.0), 1.0);
+
+        let limited = too_many("guild:1", "rate limit", 0.2);
+        assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(matches!(
+            limited,
+            ApiError::RateLimited { ref message, retry_after: 60 }
+                if message.ends_with("for this server, you can ask again in 1 minute.")
+        ));
+
+        Ok(())
+    }
+}
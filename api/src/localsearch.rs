@@ -0,0 +1,290 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use architectury::prelude::*;
+use eyre::Context;
+use serde_json::{json, Value};
+
+const DEFAULT_EXTENSIONS: &[&str] = &["md", "txt"];
+const DEFAULT_COUNT: usize = 3;
+/// Paragraphs are merged until a chunk has at least this many words
+const CHUNK_WORDS: usize = 120;
+const SNIPPET_CHARS: usize = 400;
+
+// BM25 tuning, using the usual defaults
+const K1: f64 = 1.2;
+const B: f64 = 0.75;
+
+#[derive(Debug)]
+struct Chunk {
+    path: String,
+    title: String,
+    text: String,
+    terms: HashMap<String, u32>,
+    len: usize,
+}
+
+/// An in-memory BM25 index over the paragraphs of every matching file in a directory
+#[derive(Debug, Default)]
+pub struct LocalIndex {
+    chunks: Vec<Chunk>,
+    doc_freq: HashMap<String, usize>,
+    avg_len: f64,
+}
+
+pub fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|t| !t.is_empty())
+        .map(str::to_lowercase)
+}
+
+fn collect_files(
+    dir: &Path,
+    extensions: &[String],
+    files: &mut Vec<std::path::PathBuf>,
+) -> Result<()> {
+    for entry in std::fs::read_dir(dir).context(format!("Failed to read {dir:?}"))? {
+        let path = entry?.path();
+
+        if path.is_dir() {
+            collect_files(&path, extensions, files)?;
+        } else if path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| extensions.iter().any(|e| e == ext))
+        {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Splits a file into chunks of whole paragraphs, each titled by the heading it falls under
+fn chunk_file(source: &str, fallback_title: &str) -> Vec<(String, String)> {
+    let mut chunks = vec![];
+    let mut title = fallback_title.to_string();
+    let mut current: Vec<&str> = vec![];
+    let source = source.replace("\r\n", "\n");
+
+    for paragraph in source
+        .split("\n\n")
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+    {
+        if paragraph.starts_with('#') {
+            if !current.is_empty() {
+                chunks.push((title.clone(), current.join("\n\n")));
+                current.clear();
+            }
+
+            title = paragraph
+                .lines()
+                .next()
+                .unwrap_or_default()
+                .trim_start_matches('#')
+                .trim()
+                .to_string();
+        }
+
+        current.push(paragraph);
+
+        if current
+            .iter()
+            .map(|p| p.split_whitespace().count())
+            .sum::<usize>()
+            >= CHUNK_WORDS
+        {
+            chunks.push((title.clone(), current.join("\n\n")));
+            current.clear();
+        }
+    }
+
+    if !current.is_empty() {
+        chunks.push((title, current.join("\n\n")));
+    }
+
+    chunks
+}
+
+impl LocalIndex {
+    pub fn build(root: &Path, extensions: Option<&[String]>) -> Result<Self> {
+        let extensions = extensions
+            .map(<[String]>::to_vec)
+            .unwrap_or_else(|| DEFAULT_EXTENSIONS.iter().map(|e| e.to_string()).collect());
+
+        let mut files = vec![];
+        collect_files(root, &extensions, &mut files)?;
+        files.sort();
+
+        let mut index = Self::default();
+
+        for file in &files {
+            let source =
+                std::fs::read_to_string(file).context(format!("Failed to read {file:?}"))?;
+            let path = file
+                .strip_prefix(root)
+                .unwrap_or(file)
+                .to_string_lossy()
+                .replace('\\', "/");
+
+            for (title, text) in chunk_file(&source, &path) {
+                let mut terms = HashMap::new();
+                let mut len = 0;
+
+                for term in tokenize(&title).chain(tokenize(&text)) {
+                    *terms.entry(term).or_insert(0) += 1;
+                    len += 1;
+                }
+
+                for term in terms.keys() {
+                    *index.doc_freq.entry(term.clone()).or_insert(0) += 1;
+                }
+
+                index.chunks.push(Chunk {
+                    path: path.clone(),
+                    title,
+                    text,
+                    terms,
+                    len,
+                });
+            }
+        }
+
+        index.avg_len = index.chunks.iter().map(|c| c.len).sum::<usize>() as f64
+            / index.chunks.len().max(1) as f64;
+
+        info!(
+            "Indexed {} chunk(s) from {} file(s) in {root:?}",
+            index.chunks.len(),
+            files.len()
+        );
+
+        Ok(index)
+    }
+
+    fn search(&self, query: &str, count: usize) -> Vec<&Chunk> {
+        let n = self.chunks.len() as f64;
+        let query = tokenize(query).collect::<Vec<_>>();
+
+        let mut scored = self
+            .chunks
+            .iter()
+            .map(|chunk| {
+                let score = query
+                    .iter()
+                    .filter_map(|term| {
+                        let tf = *chunk.terms.get(term)? as f64;
+                        let df = *self.doc_freq.get(term)? as f64;
+                        let idf = ((n - df + 0.5) / (df + 0.5) + 1.0).ln();
+
+                        Some(
+                            idf * (tf * (K1 + 1.0))
+                                / (tf + K1 * (1.0 - B + B * chunk.len as f64 / self.avg_len)),
+                        )
+                    })
+                    .sum::<f64>();
+
+                (chunk, score)
+            })
+            .filter(|(_, score)| *score > 0.0)
+            .collect::<Vec<_>>();
+
+        scored.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+        scored
+            .into_iter()
+            .take(count)
+            .map(|(chunk, _)| chunk)
+            .collect()
+    }
+
+    /// Searches with the `q` and `count` props, shaped like a Bing web search response
+    pub fn search_response(
+        &self,
+        props: &HashMap<String, String>,
+        url_prefix: Option<&str>,
+    ) -> Value {
+        let query = props.get("q").map(String::as_str).unwrap_or_default();
+        let count = props
+            .get("count")
+            .and_then(|count| count.trim().parse().ok())
+            .unwrap_or(DEFAULT_COUNT);
+
+        let value = self
+            .search(query, count)
+            .into_iter()
+            .map(|chunk| {
+                let snippet = chunk.text.split_whitespace().collect::<Vec<_>>().join(" ");
+                let snippet = match snippet.char_indices().nth(SNIPPET_CHARS) {
+                    Some((i, _)) => format!("{}...", &snippet[..i]),
+                    None => snippet,
+                };
+
+                json!({
+                    "name": chunk.title,
+                    "url": format!("{}{}", url_prefix.unwrap_or_default(), chunk.path),
+                    "path": chunk.path,
+                    "snippet": snippet,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        json!({
+            "_type": "SearchResponse",
+            "webPages": {
+                "value": value
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use architectury::prelude::*;
+
+    use super::{chunk_file, LocalIndex};
+
+    #[test]
+    fn ranks_matching_paragraphs() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("openchad-localsearch-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("ops"))?;
+        std::fs::write(
+            dir.join("ops/deploys.md"),
+            "# Deploys\n\nRoll back a deploy with `make rollback`.\n\n# Paging\n\nThe on-call rotation changes every Monday.",
+        )?;
+        std::fs::write(dir.join("notes.txt"), "Nothing about rollbacks in here.")?;
+        std::fs::write(dir.join("ignored.rs"), "fn deploy() {}")?;
+
+        let index = LocalIndex::build(&dir, None)?;
+        let response = index.search_response(
+            &HashMap::from([("q".into(), "how do I roll back a deploy".into())]),
+            Some("https://wiki.example.com/"),
+        );
+        std::fs::remove_dir_all(&dir)?;
+
+        let results = response["webPages"]["value"].as_array().unwrap();
+        assert_eq!(results[0]["url"], "https://wiki.example.com/ops/deploys.md");
+        assert_eq!(results[0]["name"], "Deploys");
+        assert!(results.iter().all(|r| r["path"] != "ignored.rs"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn splits_crlf_paragraphs() {
+        let chunks = chunk_file(
+            "# Setup\r\n\r\nInstall it.\r\n\r\n# Usage\r\n\r\nRun it.",
+            "file",
+        );
+
+        assert_eq!(
+            chunks,
+            [
+                ("Setup".to_string(), "# Setup\n\nInstall it.".to_string()),
+                ("Usage".to_string(), "# Usage\n\nRun it.".to_string()),
+            ]
+        );
+    }
+}
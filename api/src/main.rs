@@ -1,31 +1,47 @@
 #![feature(async_closure)]
 
+mod auth;
 mod botconfig;
+mod cache;
+mod calc;
 mod chat;
-
+mod error;
+mod feed;
+mod limits;
+mod localsearch;
+mod ocr;
+mod provider;
+mod registry;
+mod sql;
+mod token;
+mod usage;
+
+use std::collections::HashSet;
 use std::env::var;
 use std::net::SocketAddr;
 use std::str::FromStr;
-use std::sync::Arc;
 
 use architectury::coreutils::redirect;
-use architectury::log::Report;
 use architectury::prelude::*;
-use axum::http::StatusCode;
+use axum::extract::Query;
+use axum::http::{StatusCode, Uri};
+use axum::middleware;
 use axum::routing::{get, post};
 use axum::{Extension, Json, Router};
 use eyre::Context;
-use once_cell::sync::Lazy;
 use openchad_schemas::botconfig::BotConfig;
-use openchad_schemas::chat::ChatMessage;
-use openchad_schemas::HistoryBody;
+use openchad_schemas::chat::{ChatMessage, ChatRole};
+use openchad_schemas::{
+    AuditBody, FeedbackBody, HistoryBody, ProviderCacheStats, UsageQuery, UsageRow,
+};
 use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
 use sqlx::sqlite::{SqliteJournalMode, SqlitePool};
 use sqlx::{ConnectOptions, Connection, Pool, Row, Sqlite};
 
-use crate::botconfig::{create_routes, read_config};
-
-static CONFIG: Lazy<Arc<BotConfig>> = Lazy::new(|| Arc::new(read_config().unwrap()));
+use crate::botconfig::{create_routes, current_config, install_config, read_config, LoadedConfig};
+use crate::error::ApiError;
+use crate::registry::ProviderRegistry;
+use crate::token::Admin;
 
 async fn init_pool() -> Result<Pool<Sqlite>> {
     let sqlite_url = var("DATABASE_URL")?;
@@ -53,19 +69,31 @@ async fn init_pool() -> Result<Pool<Sqlite>> {
 async fn main() -> Result<()> {
     architectury::init();
 
+    if var("API_TOKEN").is_err() {
+        warn!("API_TOKEN isn't set, so the API is open to anyone and admin routes are closed");
+    }
+
     let pool = init_pool().await?;
+    cache::init(pool.clone())?;
 
-    let config = Arc::new(read_config()?);
-    let config_json = Arc::new(serde_json::to_value(config.as_ref())?);
+    let config = read_config()?;
+    registry::install(ProviderRegistry::load(&config)?);
+    install_config(LoadedConfig::new(config)?);
 
     let app = create_routes(
         Router::new()
             .route("/history", post(history))
+            .route("/feedback", post(feedback))
+            .route("/audit", post(audit))
             .route("/config", get(get_config))
-            .route("/config", post(update_config)),
-        &CONFIG,
-        config_json.clone(),
-    )?
+            // admin routes take `Admin`, so they stay closed until API_TOKEN is set
+            .route("/usage", get(usage))
+            .route("/config", post(update_config))
+            .route("/cache", get(cache_stats).delete(clear_cache))
+            .fallback(not_found),
+        &current_config().config,
+    )
+    .layer(middleware::from_fn(token::require_token))
     .layer(Extension(pool));
 
     let addr = var("API_URL")?.parse::<SocketAddr>()?;
@@ -81,51 +109,124 @@ async fn main() -> Result<()> {
 async fn history(
     Extension(pool): Extension<SqlitePool>,
     Json(body): Json<HistoryBody>,
-) -> Result<StatusCode, (StatusCode, String)> {
+) -> Result<StatusCode, ApiError> {
     append_history(
         &pool,
-        body.user,
-        ChatMessage {
-            role: "assistant".into(),
-            content: body.message,
-        },
+        body.history_key().to_owned(),
+        ChatMessage::new(ChatRole::Assistant, body.message),
     )
     .await?;
 
     Ok(StatusCode::OK)
 }
 
+async fn feedback(
+    Extension(pool): Extension<SqlitePool>,
+    Json(body): Json<FeedbackBody>,
+) -> Result<StatusCode, ApiError> {
+    sqlx::query(include_str!("../sql/FeedbackInsert.sql"))
+        .bind(body.user)
+        .bind(body.endpoint)
+        .bind(body.message)
+        .bind(body.answer)
+        .bind(if body.positive { 1 } else { -1 })
+        .execute(&pool)
+        .await
+        .context("Failed to store feedback")?;
+
+    Ok(StatusCode::OK)
+}
+
+async fn audit(
+    Extension(pool): Extension<SqlitePool>,
+    Json(body): Json<AuditBody>,
+) -> Result<StatusCode, ApiError> {
+    sqlx::query(include_str!("../sql/AuditLogInsert.sql"))
+        .bind(body.user)
+        .bind(body.user_id)
+        .bind(body.guild_id)
+        .bind(body.channel_id)
+        .bind(body.endpoint)
+        .bind(body.reason)
+        .execute(&pool)
+        .await
+        .context("Failed to append to the audit log")?;
+
+    Ok(StatusCode::OK)
+}
+
+async fn usage(
+    _: Admin,
+    Extension(pool): Extension<SqlitePool>,
+    Query(query): Query<UsageQuery>,
+) -> Result<Json<Vec<UsageRow>>, ApiError> {
+    Ok(Json(usage::report(&pool, query).await?))
+}
+
 async fn get_config() -> Json<BotConfig> {
-    Json(CONFIG.as_ref().clone())
+    Json(current_config().config.as_ref().clone())
 }
 
-async fn update_config(Json(config): Json<BotConfig>) {
+/// Swaps in a new config. The routes are fixed once the API starts, so adding or removing
+/// endpoints is turned away until it's restarted.
+async fn update_config(_: Admin, Json(config): Json<BotConfig>) -> Result<StatusCode, ApiError> {
+    let registry =
+        ProviderRegistry::load(&config).map_err(|e| ApiError::BadConfig(format!("{e:#}")))?;
+
+    let current = current_config();
+    if config.endpoints.keys().collect::<HashSet<_>>()
+        != current.config.endpoints.keys().collect::<HashSet<_>>()
+    {
+        return Err(ApiError::BadConfig(
+            "Endpoints can't be added or removed without restarting the API".into(),
+        ));
+    }
+
+    let loaded =
+        LoadedConfig::new(config.clone()).map_err(|e| ApiError::BadConfig(format!("{e:#}")))?;
+
     redirect(
-        var("CONFIG_PATH").unwrap(),
-        serde_json::to_string(&config).unwrap(),
-    )
-    .unwrap();
+        var("CONFIG_PATH").context("CONFIG_PATH isn't set")?,
+        serde_json::to_string(&config).context("Failed to serialize the config")?,
+    )?;
+
+    registry::install(registry);
+    install_config(loaded);
+
+    Ok(StatusCode::OK)
+}
+
+async fn cache_stats(_: Admin) -> Result<Json<ProviderCacheStats>, ApiError> {
+    Ok(Json(cache::stats().await?))
+}
+
+async fn clear_cache(_: Admin) -> Result<StatusCode, ApiError> {
+    cache::clear().await?;
+
+    Ok(StatusCode::OK)
 }
 
-fn internal_error_string(err: Report) -> (StatusCode, String) {
-    (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+async fn not_found(uri: Uri) -> ApiError {
+    ApiError::NotFound(uri.path().into())
 }
 
 async fn get_history(
     query: &'static str,
     pool: &SqlitePool,
     user: &str,
-) -> Result<Vec<ChatMessage>, (StatusCode, String)> {
+) -> Result<Vec<ChatMessage>, ApiError> {
     Ok(sqlx::query(query)
         .bind(user)
         .fetch_all(pool)
         .await
-        .context("Failed to query history")
-        .map_err(internal_error_string)?
+        .context("Failed to query history")?
         .into_iter()
-        .map(|h| ChatMessage {
-            role: h.get("role"),
-            content: h.get("message"),
+        .filter_map(|h| match h.get::<String, _>("role").parse() {
+            Ok(role) => Some(ChatMessage::new(role, h.get::<String, _>("message"))),
+            Err(e) => {
+                warn!("Skipping a history message: {e}");
+                None
+            }
         })
         .collect())
 }
@@ -134,15 +235,14 @@ async fn append_history(
     pool: &SqlitePool,
     username: String,
     message: ChatMessage,
-) -> Result<(), (StatusCode, String)> {
+) -> Result<(), ApiError> {
     sqlx::query(include_str!("../sql/ChatHistoryInsert.sql"))
         .bind(username)
-        .bind(message.content)
-        .bind(message.role)
+        .bind(message.content.text())
+        .bind(message.role.as_str())
         .execute(pool)
         .await
-        .context("Failed to append history")
-        .map_err(internal_error_string)?;
+        .context("Failed to append history")?;
 
     Ok(())
 }
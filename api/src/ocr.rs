@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::env::var;
+use std::process::Stdio;
+use std::time::Duration;
+
+use architectury::prelude::*;
+use base64::Engine;
+use eyre::{eyre, Context, ContextCompat};
+use reqwest::redirect::Policy;
+use reqwest::Url;
+use serde_json::{json, Value};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::error::ApiError;
+
+const DEFAULT_LANGUAGE: &str = "eng";
+const MAX_IMAGE_BYTES: usize = 10 * 1024 * 1024;
+const TIMEOUT: Duration = Duration::from_secs(30);
+// attachment URLs come from whoever calls the API, so only Discord's CDN is fetched by default
+const DEFAULT_IMAGE_HOSTS: [&str; 2] = ["cdn.discordapp.com", "media.discordapp.net"];
+
+/// Hosts images may be fetched from, `OCR_IMAGE_HOSTS` (comma separated) or Discord's CDN
+fn image_hosts() -> Vec<String> {
+    match var("OCR_IMAGE_HOSTS") {
+        Ok(hosts) => hosts
+            .split(',')
+            .map(|host| host.trim().to_lowercase())
+            .filter(|host| !host.is_empty())
+            .collect(),
+        Err(_) => DEFAULT_IMAGE_HOSTS.map(String::from).to_vec(),
+    }
+}
+
+/// `url` if it's https on one of `hosts`, so the API can't be pointed at hosts on its own network
+fn image_url(url: &str, hosts: &[String]) -> Result<Url, ApiError> {
+    let url = Url::parse(url).map_err(|e| ApiError::Provider(format!("Invalid image URL: {e}")))?;
+    let allowed = url.scheme() == "https"
+        && url
+            .host_str()
+            .is_some_and(|host| hosts.iter().any(|allowed| allowed == host));
+
+    if !allowed {
+        return Err(ApiError::Provider(format!(
+            "Images can only be read from {}",
+            hosts.join(", ")
+        )));
+    }
+
+    Ok(url)
+}
+
+/// The `data` prop (base64) if it's set, otherwise whatever the `url` prop points at
+async fn image_bytes(props: &HashMap<String, String>) -> Result<Vec<u8>> {
+    let bytes = match (props.get("data"), props.get("url")) {
+        (Some(data), _) if !data.trim().is_empty() => base64::engine::general_purpose::STANDARD
+            .decode(data.trim())
+            .context("Image data isn't valid base64")?,
+        // redirects could lead anywhere, so they aren't followed
+        (_, Some(url)) if !url.trim().is_empty() => reqwest::Client::builder()
+            .redirect(Policy::none())
+            .build()?
+            .get(image_url(url.trim(), &image_hosts())?)
+            .timeout(TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?
+            .to_vec(),
+        _ => return Err(eyre!("Either the `data` or `url` prop needs an image")),
+    };
+
+    if bytes.len() > MAX_IMAGE_BYTES {
+        return Err(eyre!(
+            "Image is {} bytes, the limit is {MAX_IMAGE_BYTES}",
+            bytes.len()
+        ));
+    }
+
+    Ok(bytes)
+}
+
+/// Reads the text in an image with the local `tesseract` binary (or `TESSERACT_PATH`), so images
+/// never leave the host. Responds with `{ text }`
+pub async fn transcribe(props: &HashMap<String, String>, language: Option<&str>) -> Result<Value> {
+    let image = image_bytes(props).await?;
+    let language = language.unwrap_or(DEFAULT_LANGUAGE);
+
+    let mut child = Command::new(var("TESSERACT_PATH").unwrap_or("tesseract".into()))
+        .args(["stdin", "stdout", "-l", language])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .context("Failed to start tesseract")?;
+
+    let mut stdin = child.stdin.take().context("tesseract has no stdin")?;
+    stdin.write_all(&image).await?;
+    drop(stdin);
+
+    let output = tokio::time::timeout(TIMEOUT, child.wait_with_output())
+        .await
+        .context("tesseract timed out")??;
+
+    if !output.status.success() {
+        return Err(eyre!(
+            "tesseract failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    let text = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    info!("Transcribed {} character(s) from image", text.len());
+
+    Ok(json!({ "text": text }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{image_url, DEFAULT_IMAGE_HOSTS};
+    use crate::error::ApiError;
+
+    #[test]
+    fn only_fetches_images_from_allowed_hosts() {
+        let hosts = DEFAULT_IMAGE_HOSTS.map(String::from);
+
+        assert!(image_url(
+            "https://cdn.discordapp.com/attachments/1/2/receipt.png?ex=1",
+            &hosts
+        )
+        .is_ok());
+        assert!(image_url("https://media.discordapp.net/attachments/1/2/a.jpg", &hosts).is_ok());
+
+        for url in [
+            "http://cdn.discordapp.com/attachments/1/2/receipt.png",
+            "https://cdn.discordapp.com.example.com/receipt.png",
+            "https://169.254.169.254/latest/meta-data/",
+            "http://localhost:8080/config",
+            "file:///etc/passwd",
+            "not a url",
+        ] {
+            assert!(
+                matches!(image_url(url, &hosts), Err(ApiError::Provider(_))),
+                "{url} was allowed"
+            );
+        }
+    }
+}
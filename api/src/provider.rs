@@ -0,0 +1,490 @@
+use std::collections::HashMap;
+
+use architectury::prelude::*;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use eyre::{eyre, Context, ContextCompat};
+use openchad_schemas::provider::{
+    BodyEncoding, Builtin, Method, Provider, ProviderAuth, ResponseType, StatusAction,
+};
+use roxmltree::{Document, Node};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::{auth, calc, feed, ocr, registry, sql};
+
+pub struct ProviderRequest {
+    pub url: String,
+    pub query: HashMap<String, String>,
+    pub body: HashMap<String, String>,
+    pub raw_body: Option<String>,
+    pub headers: HashMap<String, String>,
+    pub auth: Option<ProviderAuth>,
+    pub builtin: Option<Builtin>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ProviderResponse {
+    pub status: u16,
+    pub response: Value,
+}
+
+/// Renders the templated fields of a builtin with `render`
+pub fn render_builtin(
+    builtin: &Builtin,
+    render: impl Fn(&str) -> Result<String>,
+) -> Result<Builtin> {
+    Ok(match builtin {
+        Builtin::Sql {
+            database,
+            query,
+            params,
+            max_rows,
+        } => Builtin::Sql {
+            database: render(database)?,
+            query: query.clone(),
+            params: params.clone(),
+            max_rows: *max_rows,
+        },
+        builtin => builtin.clone(),
+    })
+}
+
+/// Runs a provider, either in-process for builtins or as an HTTP request
+pub async fn execute(
+    name: &str,
+    provider: &Provider,
+    request: ProviderRequest,
+) -> Result<ProviderResponse> {
+    let Some(builtin) = request.builtin.clone() else {
+        return send_request(provider, request).await;
+    };
+
+    let props: HashMap<String, String> = request.body.into_iter().chain(request.query).collect();
+
+    let response = match builtin {
+        Builtin::LocalSearch { url_prefix, .. } => registry::registry()
+            .index(name)
+            .context(format!("Provider `{name}` hasn't been indexed"))?
+            .search_response(&props, url_prefix.as_deref()),
+        Builtin::Sql {
+            database,
+            query,
+            params,
+            max_rows,
+        } => {
+            let params = params
+                .iter()
+                .map(|param| props.get(param).cloned())
+                .collect();
+            sql::query(&database, &query, params, max_rows).await?
+        }
+        Builtin::Feeds {
+            urls,
+            refresh,
+            max_age_days,
+        } => feed::search_response(&urls, refresh, max_age_days, &props).await,
+        Builtin::Ocr { language } => ocr::transcribe(&props, language.as_deref()).await?,
+        Builtin::Calculator => {
+            let expression = props.get("expression").map(String::as_str);
+
+            return Ok(match calc::evaluate(expression.unwrap_or_default()) {
+                Ok(response) => ProviderResponse {
+                    status: 200,
+                    response,
+                },
+                // bad input is the caller's problem, so let transforms (or the model) see why
+                Err(e) => ProviderResponse {
+                    status: 422,
+                    response: json!({ "error": e.to_string() }),
+                },
+            });
+        }
+    };
+
+    Ok(ProviderResponse {
+        status: 200,
+        response,
+    })
+}
+
+pub async fn send_request(
+    provider: &Provider,
+    request: ProviderRequest,
+) -> Result<ProviderResponse> {
+    let method = provider.method.clone().unwrap_or_default();
+    let client = reqwest::Client::new();
+
+    let mut headers = HeaderMap::new();
+    for (k, v) in &request.headers {
+        headers.insert(
+            HeaderName::from_bytes(k.as_bytes()).context(format!("Invalid header name {k:?}"))?,
+            HeaderValue::from_str(v).context(format!("Invalid value for header {k:?}"))?,
+        );
+    }
+
+    let mut builder = match method {
+        Method::Get => client.get(&request.url),
+        Method::Post => client.post(&request.url),
+        Method::Put => client.put(&request.url),
+    }
+    .query(&request.query);
+
+    builder = match provider.body_encoding.clone().unwrap_or_default() {
+        BodyEncoding::Json if !request.body.is_empty() => builder.json(&request.body),
+        BodyEncoding::Json => builder,
+        BodyEncoding::Form => builder.form(&request.body),
+        BodyEncoding::Raw => builder.body(request.raw_body.unwrap_or_default()),
+    };
+
+    // set after the body so it overrides the encoding's default content type
+    if let Some(content_type) = &provider.content_type {
+        headers.insert(CONTENT_TYPE, HeaderValue::from_str(content_type)?);
+    }
+
+    let builder = builder.headers(headers);
+    let response = match &request.auth {
+        Some(auth) => auth::send_authorized(builder, auth).await?,
+        None => builder.send().await?,
+    };
+    let status = response.status();
+
+    if !accepted(provider, status.as_u16()) {
+        let rule = provider.status.clone().unwrap_or_default();
+
+        match rule.on_error.unwrap_or_default() {
+            StatusAction::Fail => {
+                return Err(eyre!(
+                    "Provider responded with {status}: {}",
+                    response.text().await.unwrap_or_default()
+                ))
+            }
+            StatusAction::Empty => {
+                warn!("Provider responded with {status}, continuing with an empty response");
+                return Ok(ProviderResponse {
+                    status: status.as_u16(),
+                    response: Value::Null,
+                });
+            }
+            StatusAction::Passthrough => {}
+        }
+    }
+
+    let text = response.text().await?;
+
+    Ok(ProviderResponse {
+        status: status.as_u16(),
+        response: parse_response(&text, &provider.response_type.clone().unwrap_or_default())?,
+    })
+}
+
+/// Whether `status` is a success by the provider's `status.accept` rule
+pub fn accepted(provider: &Provider, status: u16) -> bool {
+    match provider
+        .status
+        .as_ref()
+        .and_then(|rule| rule.accept.as_ref())
+    {
+        Some(codes) => codes.contains(&status),
+        None => (200..300).contains(&status),
+    }
+}
+
+pub fn parse_response(text: &str, response_type: &ResponseType) -> Result<Value> {
+    Ok(match response_type {
+        ResponseType::Json => serde_json::from_str(text).context("Invalid JSON response")?,
+        ResponseType::Text => Value::String(text.to_string()),
+        ResponseType::Xml => {
+            let doc = Document::parse(text).context("Invalid XML response")?;
+            let root = doc.root_element();
+            json!({ root.tag_name().name(): xml_to_value(root) })
+        }
+        ResponseType::Rss => {
+            feed::feed_to_value(&Document::parse(text).context("Invalid RSS/Atom response")?)
+        }
+    })
+}
+
+/// Elements become objects keyed by tag name, attributes are prefixed with `@`, and repeated
+/// children are collected into arrays. Text-only elements collapse into strings.
+pub fn xml_to_value(node: Node) -> Value {
+    let mut map = Map::new();
+
+    for attr in node.attributes() {
+        map.insert(
+            format!("@{}", attr.name()),
+            Value::String(attr.value().into()),
+        );
+    }
+
+    for child in node.children().filter(|c| c.is_element()) {
+        let name = child.tag_name().name().to_string();
+        let value = xml_to_value(child);
+
+        match map.get_mut(&name) {
+            Some(Value::Array(values)) => values.push(value),
+            Some(existing) => *existing = Value::Array(vec![existing.take(), value]),
+            None => {
+                map.insert(name, value);
+            }
+        }
+    }
+
+    let text = node
+        .children()
+        .filter(|c| c.is_text())
+        .filter_map(|c| c.text())
+        .collect::<String>()
+        .trim()
+        .to_string();
+
+    if map.is_empty() {
+        Value::String(text)
+    } else {
+        if !text.is_empty() {
+            map.insert("#text".into(), Value::String(text));
+        }
+        Value::Object(map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+
+    use architectury::prelude::*;
+    use axum::extract::Path;
+    use axum::http::header::CONTENT_TYPE;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::any;
+    use axum::{Json, Router};
+    use openchad_schemas::provider::{Provider, ResponseType};
+    use roxmltree::Document;
+    use serde_json::{json, Value};
+
+    use super::{accepted, parse_response, send_request, xml_to_value, ProviderRequest};
+
+    const XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<current>
+    <city id="2643743" name="London">
+        <coord lon="-0.1257" lat="51.5085"/>
+        <country>GB</country>
+    </city>
+    <temperature value="284.2" min="282.9" max="285.4" unit="kelvin"/>
+    <weather number="500" value="light rain" icon="10d">Bring an umbrella</weather>
+    <alerts>
+        <alert>Flood warning</alert>
+        <alert>Wind advisory</alert>
+    </alerts>
+</current>
+"#;
+
+    const RSS: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0">
+<channel>
+    <title>World News</title>
+    <item>
+        <title>Volcano erupts near Reykjavik</title>
+        <link>https://news.example.com/world/volcano</link>
+        <pubDate>Mon, 12 Oct 2026 08:15:00 GMT</pubDate>
+    </item>
+</channel>
+</rss>
+"#;
+
+    fn provider(options: Value) -> Provider {
+        let mut provider = json!({ "propRules": [] });
+        provider
+            .as_object_mut()
+            .unwrap()
+            .extend(options.as_object().unwrap().clone());
+
+        serde_json::from_value(provider).unwrap()
+    }
+
+    fn request(url: String) -> ProviderRequest {
+        ProviderRequest {
+            url,
+            query: HashMap::new(),
+            body: HashMap::from([("q".into(), "volcano".into())]),
+            raw_body: None,
+            headers: HashMap::new(),
+            auth: None,
+            builtin: None,
+        }
+    }
+
+    /// Answers `/{status}` with that status and echoes the request back as JSON
+    async fn serve() -> String {
+        async fn echo(
+            Path(status): Path<u16>,
+            method: axum::http::Method,
+            headers: HeaderMap,
+            body: String,
+        ) -> (StatusCode, Json<Value>) {
+            (
+                StatusCode::from_u16(status).unwrap(),
+                Json(json!({
+                    "method": method.as_str(),
+                    "contentType": headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()),
+                    "body": body,
+                })),
+            )
+        }
+
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(
+            Router::new()
+                .route("/:status", any(echo))
+                .into_make_service(),
+        );
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+
+        url
+    }
+
+    #[test]
+    fn converts_recorded_xml() -> Result<()> {
+        let doc = Document::parse(XML)?;
+
+        assert_eq!(
+            xml_to_value(doc.root_element()),
+            json!({
+                "city": {
+                    "@id": "2643743",
+                    "@name": "London",
+                    "coord": { "@lon": "-0.1257", "@lat": "51.5085" },
+                    "country": "GB",
+                },
+                "temperature": {
+                    "@value": "284.2",
+                    "@min": "282.9",
+                    "@max": "285.4",
+                    "@unit": "kelvin",
+                },
+                "weather": {
+                    "@number": "500",
+                    "@value": "light rain",
+                    "@icon": "10d",
+                    "#text": "Bring an umbrella",
+                },
+                "alerts": { "alert": ["Flood warning", "Wind advisory"] },
+            })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn parses_each_response_type() -> Result<()> {
+        assert_eq!(
+            parse_response(r#"{"q": "volcano"}"#, &ResponseType::Json)?,
+            json!({ "q": "volcano" })
+        );
+        assert!(parse_response("volcano", &ResponseType::Json).is_err());
+
+        assert_eq!(
+            parse_response(" volcano\n", &ResponseType::Text)?,
+            json!(" volcano\n")
+        );
+
+        let xml = parse_response(XML, &ResponseType::Xml)?;
+        assert_eq!(xml["current"]["city"]["country"], "GB");
+        assert!(parse_response("<current>", &ResponseType::Xml).is_err());
+
+        let rss = parse_response(RSS, &ResponseType::Rss)?;
+        assert_eq!(rss["title"], "World News");
+        assert_eq!(rss["items"][0]["title"], "Volcano erupts near Reykjavik");
+        assert_eq!(
+            rss["items"][0]["link"],
+            "https://news.example.com/world/volcano"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn applies_status_rules() -> Result<()> {
+        let url = serve().await;
+
+        let ok = send_request(&provider(json!({})), request(format!("{url}/200"))).await?;
+        assert_eq!(ok.status, 200);
+        assert_eq!(ok.response["body"], r#"{"q":"volcano"}"#);
+
+        let failed = send_request(&provider(json!({})), request(format!("{url}/404"))).await;
+        assert!(
+            matches!(failed, Err(e) if e.to_string().starts_with("Provider responded with 404"))
+        );
+
+        let empty = provider(json!({ "status": { "onError": "empty" } }));
+        let empty = send_request(&empty, request(format!("{url}/404"))).await?;
+        assert_eq!(empty.status, 404);
+        assert_eq!(empty.response, Value::Null);
+
+        let passthrough = provider(json!({ "status": { "onError": "passthrough" } }));
+        let passthrough = send_request(&passthrough, request(format!("{url}/500"))).await?;
+        assert_eq!(passthrough.status, 500);
+        assert_eq!(passthrough.response["method"], "GET");
+
+        // an explicit list replaces the 2xx default, so a 200 can be an error too
+        let listed = provider(json!({ "status": { "accept": [404] } }));
+        assert!(accepted(&listed, 404));
+        assert!(!accepted(&listed, 200));
+        assert_eq!(
+            send_request(&listed, request(format!("{url}/404")))
+                .await?
+                .status,
+            404
+        );
+        assert!(send_request(&listed, request(format!("{url}/200")))
+            .await
+            .is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn content_type_overrides_the_body_encoding() -> Result<()> {
+        let url = serve().await;
+
+        let form = provider(json!({ "method": "POST", "bodyEncoding": "form" }));
+        let form = send_request(&form, request(format!("{url}/200"))).await?;
+        assert_eq!(
+            form.response,
+            json!({
+                "method": "POST",
+                "contentType": "application/x-www-form-urlencoded",
+                "body": "q=volcano",
+            })
+        );
+
+        let raw = provider(json!({
+            "method": "PUT",
+            "bodyEncoding": "raw",
+            "contentType": "application/xml",
+        }));
+        let raw = send_request(
+            &raw,
+            ProviderRequest {
+                raw_body: Some("<q>volcano</q>".into()),
+                ..request(format!("{url}/200"))
+            },
+        )
+        .await?;
+        assert_eq!(
+            raw.response,
+            json!({
+                "method": "PUT",
+                "contentType": "application/xml",
+                "body": "<q>volcano</q>",
+            })
+        );
+
+        let json = provider(json!({ "contentType": "application/vnd.api+json" }));
+        let json = send_request(&json, request(format!("{url}/200"))).await?;
+        assert_eq!(json.response["contentType"], "application/vnd.api+json");
+
+        Ok(())
+    }
+}
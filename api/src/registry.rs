@@ -0,0 +1,464 @@
+use std::collections::{HashMap, HashSet};
+use std::env::var;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use architectury::prelude::*;
+use jsonschema::JSONSchema;
+use once_cell::sync::Lazy;
+use openchad_schemas::botconfig::{BotConfig, ConfigOptionKind};
+use openchad_schemas::provider::{Builtin, Provider};
+use serde_json::Value;
+
+use crate::localsearch::LocalIndex;
+
+const SCHEMA_FILE: &str = "providers.schema.json";
+
+static REGISTRY: Lazy<RwLock<Arc<ProviderRegistry>>> =
+    Lazy::new(|| RwLock::new(Arc::new(ProviderRegistry::default())));
+
+#[derive(Debug)]
+pub enum RegistryError {
+    Read {
+        path: PathBuf,
+        error: String,
+    },
+    Parse {
+        provider: String,
+        error: String,
+    },
+    Schema {
+        provider: String,
+        errors: Vec<String>,
+    },
+    UnknownProvider {
+        task: String,
+        provider: String,
+    },
+    UnknownProp {
+        task: String,
+        provider: String,
+        prop: String,
+    },
+    MissingProp {
+        task: String,
+        provider: String,
+        prop: String,
+    },
+    MissingUrl {
+        provider: String,
+    },
+    UnknownTask {
+        task: String,
+        function: String,
+        target: String,
+    },
+    UnknownAutocomplete {
+        endpoint: String,
+        option: String,
+        target: String,
+    },
+    Index {
+        provider: String,
+        error: String,
+    },
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Read { path, error } => write!(f, "Failed to read {path:?}: {error}"),
+            Self::Parse { provider, error } => {
+                write!(f, "Provider `{provider}` is invalid: {error}")
+            }
+            Self::Schema { provider, errors } => write!(
+                f,
+                "Provider `{provider}` doesn't match {SCHEMA_FILE}: {}",
+                errors.join("; ")
+            ),
+            Self::UnknownProvider { task, provider } => {
+                write!(f, "`{task}` uses `{provider}`, which isn't a provider")
+            }
+            Self::UnknownProp {
+                task,
+                provider,
+                prop,
+            } => write!(
+                f,
+                "`{task}` sets prop {prop:?}, which has no prop rule in provider `{provider}`"
+            ),
+            Self::MissingProp {
+                task,
+                provider,
+                prop,
+            } => write!(
+                f,
+                "`{task}` is missing prop {prop:?}, which is required by provider `{provider}`"
+            ),
+            Self::MissingUrl { provider } => {
+                write!(
+                    f,
+                    "Provider `{provider}` needs either a `url` or a `builtin`"
+                )
+            }
+            Self::UnknownTask {
+                task,
+                function,
+                target,
+            } => write!(
+                f,
+                "`{task}` function `{function}` calls `{target}`, which isn't a `providers.*` task"
+            ),
+            Self::UnknownAutocomplete {
+                endpoint,
+                option,
+                target,
+            } => write!(
+                f,
+                "`{endpoint}` option `{option}` autocompletes from `{target}`, which isn't a `providers.*` task"
+            ),
+            Self::Index { provider, error } => {
+                write!(
+                    f,
+                    "Failed to index files for provider `{provider}`: {error}"
+                )
+            }
+        }
+    }
+}
+
+/// Every problem found while loading providers, so they can all be fixed in one go
+#[derive(Debug)]
+pub struct RegistryErrors(pub Vec<RegistryError>);
+
+impl fmt::Display for RegistryErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Found {} provider error(s)", self.0.len())?;
+        for error in &self.0 {
+            write!(f, "\n  - {error}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RegistryErrors {}
+
+#[derive(Debug, Default)]
+pub struct ProviderRegistry {
+    providers: HashMap<String, Provider>,
+    indexes: HashMap<String, Arc<LocalIndex>>,
+}
+
+impl ProviderRegistry {
+    /// Loads every provider definition in `PROVIDERS_PATH` and checks `config` against them
+    pub fn load(config: &BotConfig) -> Result<Self, RegistryErrors> {
+        let dir = var("PROVIDERS_PATH").map_err(|e| {
+            RegistryErrors(vec![RegistryError::Read {
+                path: "$PROVIDERS_PATH".into(),
+                error: e.to_string(),
+            }])
+        })?;
+
+        Self::load_from(Path::new(&dir), config)
+    }
+
+    pub fn load_from(dir: &Path, config: &BotConfig) -> Result<Self, RegistryErrors> {
+        let mut errors = vec![];
+        let mut providers = HashMap::new();
+
+        let schema = read_json(&dir.join(SCHEMA_FILE)).and_then(|schema| {
+            JSONSchema::compile(&schema).map_err(|e| RegistryError::Parse {
+                provider: SCHEMA_FILE.into(),
+                error: e.to_string(),
+            })
+        });
+
+        let schema = match schema {
+            Ok(schema) => schema,
+            Err(e) => return Err(RegistryErrors(vec![e])),
+        };
+
+        let entries = std::fs::read_dir(dir).map_err(|e| {
+            RegistryErrors(vec![RegistryError::Read {
+                path: dir.into(),
+                error: e.to_string(),
+            }])
+        })?;
+
+        for path in entries.filter_map(|entry| entry.ok().map(|entry| entry.path())) {
+            let Some(name) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .filter(|name| *name != SCHEMA_FILE)
+                .and_then(|name| name.strip_suffix(".json"))
+                .map(String::from)
+            else {
+                continue;
+            };
+
+            match parse_provider(&name, &path, &schema) {
+                Ok(provider) => {
+                    providers.insert(name, provider);
+                }
+                Err(e) => errors.push(e),
+            }
+        }
+
+        let mut indexes = HashMap::new();
+
+        for (name, provider) in &providers {
+            match &provider.builtin {
+                Some(Builtin::LocalSearch {
+                    path, extensions, ..
+                }) => match LocalIndex::build(&dir.join(path), extensions.as_deref()) {
+                    Ok(index) => {
+                        indexes.insert(name.clone(), Arc::new(index));
+                    }
+                    Err(e) => errors.push(RegistryError::Index {
+                        provider: name.clone(),
+                        error: e.to_string(),
+                    }),
+                },
+                Some(Builtin::Sql { params, .. }) => {
+                    let props = provider
+                        .prop_rules
+                        .iter()
+                        .flat_map(|rule| rule.props.iter())
+                        .collect::<HashSet<_>>();
+
+                    errors.extend(params.iter().filter(|param| !props.contains(param)).map(
+                        |param| RegistryError::Parse {
+                            provider: name.clone(),
+                            error: format!("query param {param:?} has no prop rule"),
+                        },
+                    ));
+                }
+                Some(Builtin::Feeds { urls, .. }) if urls.is_empty() => {
+                    errors.push(RegistryError::Parse {
+                        provider: name.clone(),
+                        error: "feeds builtin needs at least one url".into(),
+                    })
+                }
+                Some(Builtin::Feeds { .. } | Builtin::Calculator | Builtin::Ocr { .. }) => {}
+                None if provider.url.is_none() => errors.push(RegistryError::MissingUrl {
+                    provider: name.clone(),
+                }),
+                None => {}
+            }
+        }
+
+        let registry = Self { providers, indexes };
+        errors.extend(registry.check_config(config));
+
+        if errors.is_empty() {
+            info!(
+                "Loaded {} provider(s): {:?}",
+                registry.providers.len(),
+                registry.providers.keys().collect::<Vec<_>>()
+            );
+            Ok(registry)
+        } else {
+            Err(RegistryErrors(errors))
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Provider> {
+        self.providers.get(name)
+    }
+
+    pub fn index(&self, name: &str) -> Option<Arc<LocalIndex>> {
+        self.indexes.get(name).cloned()
+    }
+
+    /// Makes sure every `providers.*` task points at a known provider and covers its required props,
+    /// and that tool functions and autocompleting options only call `providers.*` tasks
+    fn check_config(&self, config: &BotConfig) -> Vec<RegistryError> {
+        let mut errors = vec![];
+
+        for (name, config_provider) in &config.providers {
+            let task = format!("providers.{name}");
+
+            let Some(provider) = self.get(&config_provider.provider) else {
+                errors.push(RegistryError::UnknownProvider {
+                    task,
+                    provider: config_provider.provider.clone(),
+                });
+                continue;
+            };
+
+            let known_props = provider
+                .prop_rules
+                .iter()
+                .flat_map(|rule| rule.props.iter())
+                .collect::<HashSet<_>>();
+
+            for prop in config_provider.props.keys() {
+                if !known_props.contains(prop) {
+                    errors.push(RegistryError::UnknownProp {
+                        task: task.clone(),
+                        provider: config_provider.provider.clone(),
+                        prop: prop.clone(),
+                    });
+                }
+            }
+
+            for prop in provider
+                .prop_rules
+                .iter()
+                .filter(|rule| rule.required)
+                .flat_map(|rule| rule.props.iter())
+            {
+                if !config_provider.props.contains_key(prop) {
+                    errors.push(RegistryError::MissingProp {
+                        task: task.clone(),
+                        provider: config_provider.provider.clone(),
+                        prop: prop.clone(),
+                    });
+                }
+            }
+        }
+
+        for (name, tool) in config.tools.iter().flatten() {
+            for (function, options) in &tool.functions {
+                let known = options
+                    .task
+                    .strip_prefix("providers.")
+                    .is_some_and(|provider| config.providers.contains_key(provider));
+
+                if !known {
+                    errors.push(RegistryError::UnknownTask {
+                        task: format!("tools.{name}"),
+                        function: function.clone(),
+                        target: options.task.clone(),
+                    });
+                }
+            }
+        }
+
+        for (url, endpoint) in &config.endpoints {
+            for (option, options) in endpoint.options.iter().flatten() {
+                let ConfigOptionKind::String {
+                    autocomplete: Some(target),
+                    ..
+                } = &options.kind
+                else {
+                    continue;
+                };
+
+                let known = target
+                    .strip_prefix("providers.")
+                    .is_some_and(|provider| config.providers.contains_key(provider));
+
+                if !known {
+                    errors.push(RegistryError::UnknownAutocomplete {
+                        endpoint: url.clone(),
+                        option: option.clone(),
+                        target: target.clone(),
+                    });
+                }
+            }
+        }
+
+        errors
+    }
+}
+
+fn read_json(path: &Path) -> Result<Value, RegistryError> {
+    let source = std::fs::read_to_string(path).map_err(|e| RegistryError::Read {
+        path: path.into(),
+        error: e.to_string(),
+    })?;
+
+    serde_json::from_str(&source).map_err(|e| RegistryError::Parse {
+        provider: path.display().to_string(),
+        error: e.to_string(),
+    })
+}
+
+fn parse_provider(name: &str, path: &Path, schema: &JSONSchema) -> Result<Provider, RegistryError> {
+    let value = read_json(path)?;
+
+    if let Err(errors) = schema.validate(&value) {
+        return Err(RegistryError::Schema {
+            provider: name.into(),
+            errors: errors
+                .map(|e| format!("{} at {}", e, e.instance_path))
+                .collect(),
+        });
+    }
+
+    serde_json::from_value(value).map_err(|e| RegistryError::Parse {
+        provider: name.into(),
+        error: e.to_string(),
+    })
+}
+
+/// The registry that's currently in use
+pub fn registry() -> Arc<ProviderRegistry> {
+    REGISTRY.read().unwrap().clone()
+}
+
+pub fn install(registry: ProviderRegistry) {
+    *REGISTRY.write().unwrap() = Arc::new(registry);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use architectury::coreutils::cat;
+    use architectury::prelude::*;
+    use openchad_schemas::botconfig::BotConfig;
+
+    use super::{ProviderRegistry, RegistryError};
+
+    #[test]
+    fn load_providers() -> Result<()> {
+        let config = serde_json::from_str::<BotConfig>(&cat("../bot.json")?)?;
+        let registry = ProviderRegistry::load_from(Path::new("../providers"), &config)?;
+
+        assert!(registry.get("bing").is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn missing_required_prop() -> Result<()> {
+        let mut config = serde_json::from_str::<BotConfig>(&cat("../bot.json")?)?;
+        config
+            .providers
+            .get_mut("searchContext")
+            .unwrap()
+            .props
+            .remove("q");
+
+        let errors = ProviderRegistry::load_from(Path::new("../providers"), &config)
+            .unwrap_err()
+            .0;
+
+        assert!(matches!(
+            errors.as_slice(),
+            [RegistryError::MissingProp { prop, .. }] if prop == "q"
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_autocomplete_task() -> Result<()> {
+        let mut config = serde_json::from_str::<BotConfig>(&cat("../bot.json")?)?;
+        config.providers.remove("suggestPlaces");
+
+        let errors = ProviderRegistry::load_from(Path::new("../providers"), &config)
+            .unwrap_err()
+            .0;
+
+        assert!(matches!(
+            errors.as_slice(),
+            [RegistryError::UnknownAutocomplete { target, .. }] if target == "providers.suggestPlaces"
+        ));
+
+        Ok(())
+    }
+}
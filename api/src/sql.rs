@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use architectury::prelude::*;
+use eyre::Context;
+use futures::{StreamExt, TryStreamExt};
+use once_cell::sync::Lazy;
+use serde_json::{json, Map, Value};
+use sqlx::any::{AnyConnectOptions, AnyKind, AnyPool, AnyPoolOptions, AnyRow};
+use sqlx::{Column, Row};
+use tokio::sync::Mutex;
+
+const DEFAULT_MAX_ROWS: usize = 50;
+
+/// Pools are opened on first use and kept around, keyed by connection URL
+static POOLS: Lazy<Mutex<HashMap<String, AnyPool>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+async fn pool(database: &str) -> Result<AnyPool> {
+    let mut pools = POOLS.lock().await;
+
+    if let Some(pool) = pools.get(database) {
+        return Ok(pool.clone());
+    }
+
+    let mut options =
+        AnyConnectOptions::from_str(database).context("Invalid database connection URL")?;
+
+    if let Some(sqlite) = options.as_sqlite_mut() {
+        *sqlite = sqlite.clone().read_only(true);
+    }
+
+    let pool = AnyPoolOptions::new()
+        .max_connections(2)
+        .connect_with(options)
+        .await
+        .context("Failed to connect to database")?;
+
+    pools.insert(database.to_string(), pool.clone());
+
+    Ok(pool)
+}
+
+fn column_value(row: &AnyRow, i: usize) -> Value {
+    if let Ok(v) = row.try_get::<Option<i64>, _>(i) {
+        json!(v)
+    } else if let Ok(v) = row.try_get::<Option<f64>, _>(i) {
+        json!(v)
+    } else if let Ok(v) = row.try_get::<Option<String>, _>(i) {
+        json!(v)
+    } else if let Ok(v) = row.try_get::<Option<bool>, _>(i) {
+        json!(v)
+    } else {
+        Value::Null
+    }
+}
+
+/// Runs `query` with `params` bound in order, inside a read-only transaction
+pub async fn query(
+    database: &str,
+    query: &str,
+    params: Vec<Option<String>>,
+    max_rows: Option<usize>,
+) -> Result<Value> {
+    let pool = pool(database).await?;
+    let mut tx = pool.begin().await?;
+
+    // SQLite connections are already opened read-only
+    if pool.any_kind() == AnyKind::Postgres {
+        sqlx::query("set transaction read only")
+            .execute(&mut tx)
+            .await?;
+    }
+
+    let max_rows = max_rows.unwrap_or(DEFAULT_MAX_ROWS);
+
+    let rows = params
+        .into_iter()
+        .fold(sqlx::query(query), |query, param| query.bind(param))
+        .fetch(&mut tx)
+        .take(max_rows)
+        .map_ok(|row| {
+            row.columns()
+                .iter()
+                .map(|column| {
+                    (
+                        column.name().to_string(),
+                        column_value(&row, column.ordinal()),
+                    )
+                })
+                .collect::<Map<_, _>>()
+        })
+        .try_collect::<Vec<_>>()
+        .await
+        .context("Query failed")?;
+
+    if rows.len() == max_rows {
+        warn!("Query hit the limit of {max_rows} rows, the rest were skipped");
+    }
+
+    tx.rollback().await?;
+
+    Ok(json!({ "rows": rows }))
+}
+
+#[cfg(test)]
+mod tests {
+    use architectury::prelude::*;
+    use serde_json::json;
+    use sqlx::sqlite::SqliteConnectOptions;
+    use sqlx::{ConnectOptions, Connection};
+
+    #[tokio::test]
+    async fn binds_params_read_only() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("openchad-sql-{}.db", std::process::id()));
+        let database = format!("sqlite://{}", path.display());
+
+        let mut conn = SqliteConnectOptions::new()
+            .filename(&path)
+            .create_if_missing(true)
+            .connect()
+            .await?;
+        sqlx::query("create table Tickets (title text, status text, points real)")
+            .execute(&mut conn)
+            .await?;
+        sqlx::query("insert into Tickets values ('a', 'open', 1.5), ('b', 'open', null), ('c', 'closed', 3)")
+            .execute(&mut conn)
+            .await?;
+        conn.close().await?;
+
+        let response = super::query(
+            &database,
+            "select count(*) as open, sum(points) as points from Tickets where status = $1",
+            vec![Some("open".into())],
+            None,
+        )
+        .await?;
+
+        let limited = super::query(
+            &database,
+            "select title from Tickets order by title",
+            vec![],
+            Some(2),
+        )
+        .await?;
+
+        let write = super::query(&database, "delete from Tickets", vec![], None).await;
+        std::fs::remove_file(&path)?;
+
+        assert_eq!(response, json!({ "rows": [{ "open": 2, "points": 1.5 }] }));
+        assert_eq!(
+            limited,
+            json!({ "rows": [{ "title": "a" }, { "title": "b" }] })
+        );
+        assert!(write.is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+use std::env::var;
+
+use axum::async_trait;
+use axum::extract::FromRequestParts;
+use axum::http::header::AUTHORIZATION;
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use once_cell::sync::Lazy;
+
+use crate::error::ApiError;
+
+static API_TOKEN: Lazy<Option<String>> =
+    Lazy::new(|| var("API_TOKEN").ok().filter(|token| !token.is_empty()));
+
+/// Turns away requests that don't send `API_TOKEN` as a bearer token. Without it set, anyone who
+/// can reach the API can use it
+pub async fn require_token<B>(request: Request<B>, next: Next<B>) -> Result<Response, ApiError> {
+    if !authorized(API_TOKEN.as_deref(), request.headers()) {
+        return Err(ApiError::Unauthorized("Missing or wrong API token".into()));
+    }
+
+    Ok(next.run(request).await)
+}
+
+/// Taken by admin routes, which stay closed until `API_TOKEN` is set since they'd otherwise be open
+/// to anyone who can reach the API
+pub struct Admin;
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Admin {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, ApiError> {
+        match API_TOKEN.as_deref() {
+            Some(token) if authorized(Some(token), &parts.headers) => Ok(Admin),
+            Some(_) => Err(ApiError::Unauthorized("Missing or wrong API token".into())),
+            None => Err(ApiError::Unauthorized(
+                "Admin routes are closed until API_TOKEN is set".into(),
+            )),
+        }
+    }
+}
+
+fn authorized(token: Option<&str>, headers: &HeaderMap) -> bool {
+    let Some(token) = token else {
+        return true;
+    };
+
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|sent| sent == token)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::header::AUTHORIZATION;
+    use axum::http::HeaderMap;
+
+    use super::authorized;
+
+    #[test]
+    fn checks_bearer_tokens() {
+        let headers = |value: &str| HeaderMap::from_iter([(AUTHORIZATION, value.parse().unwrap())]);
+
+        assert!(authorized(None, &HeaderMap::new()));
+        assert!(authorized(Some("secret"), &headers("Bearer secret")));
+        assert!(!authorized(Some("secret"), &HeaderMap::new()));
+        assert!(!authorized(Some("secret"), &headers("Bearer wrong")));
+        assert!(!authorized(Some("secret"), &headers("Basic secret")));
+    }
+}
@@ -0,0 +1,205 @@
+use architectury::prelude::*;
+use eyre::Context;
+use openchad_schemas::chat::ChatUsage;
+use openchad_schemas::{UsageQuery, UsageRow};
+use sqlx::{Row, SqlitePool};
+
+use crate::limits::{self, Caller};
+
+const DEFAULT_USAGE_DAYS: u32 = 7;
+
+/// Dollars per 1K prompt and completion tokens
+fn price(model: &str) -> (f64, f64) {
+    match model {
+        m if m.starts_with("gpt-4o-mini") => (0.00015, 0.0006),
+        m if m.starts_with("gpt-4o") => (0.005, 0.015),
+        m if m.starts_with("gpt-4-turbo") => (0.01, 0.03),
+        m if m.starts_with("gpt-4") => (0.03, 0.06),
+        m if m.starts_with("gpt-3.5-turbo") => (0.0015, 0.002),
+        m => {
+            warn!("No price for {m}, counting it as gpt-4");
+            (0.03, 0.06)
+        }
+    }
+}
+
+fn cost(model: &str, prompt: u64, completion: u64) -> f64 {
+    let (prompt_price, completion_price) = price(model);
+    (prompt as f64 * prompt_price + completion as f64 * completion_price) / 1000.0
+}
+
+/// An LLM call made for the request being handled. It's recorded once it's dropped, with the
+/// token counts the API reports or, failing that, estimates of the prompt and what was answered
+pub struct Call {
+    caller: Option<Caller>,
+    model: String,
+    step: String,
+    prompt: usize,
+    completion: String,
+    usage: Option<ChatUsage>,
+}
+
+impl Call {
+    pub fn new(model: &str, step: &str, prompt: usize) -> Self {
+        Self {
+            caller: limits::current(),
+            model: model.into(),
+            step: step.into(),
+            prompt,
+            completion: String::new(),
+            usage: None,
+        }
+    }
+
+    pub fn push(&mut self, completion: &str) {
+        self.completion.push_str(completion);
+    }
+
+    pub fn usage(&mut self, usage: ChatUsage) {
+        self.usage = Some(usage);
+    }
+}
+
+impl Drop for Call {
+    fn drop(&mut self) {
+        let (prompt, completion, estimated) = match &self.usage {
+            Some(usage) => (
+                usage.prompt_tokens as u64,
+                usage.completion_tokens as u64,
+                false,
+            ),
+            None => (
+                self.prompt as u64,
+                crate::chat::estimate_tokens(&self.completion) as u64,
+                true,
+            ),
+        };
+        let cost = cost(&self.model, prompt, completion);
+
+        info!(
+            "<{}> {} used {prompt}+{completion} tokens{} (~{:.3}¢)",
+            self.step,
+            self.model,
+            if estimated { " (estimated)" } else { "" },
+            cost * 100.0
+        );
+
+        let Some(caller) = self.caller.take() else {
+            return;
+        };
+        caller.spend((prompt + completion) as usize, cost);
+
+        let model = self.model.clone();
+        let step = self.step.clone();
+
+        tokio::spawn(async move {
+            let result = sqlx::query(include_str!("../sql/UsageInsert.sql"))
+                .bind(&caller.user)
+                .bind(&caller.user_id)
+                .bind(&caller.guild)
+                .bind(&caller.endpoint)
+                .bind(step)
+                .bind(model)
+                .bind(prompt as i64)
+                .bind(completion as i64)
+                .bind(estimated)
+                .bind(cost)
+                .execute(&caller.pool)
+                .await;
+
+            if let Err(e) = result {
+                warn!("Failed to record usage: {e}");
+            }
+        });
+    }
+}
+
+/// Usage by day, user and endpoint, most recent and most expensive first
+pub async fn report(pool: &SqlitePool, query: UsageQuery) -> Result<Vec<UsageRow>> {
+    let days = query.days.unwrap_or(DEFAULT_USAGE_DAYS);
+
+    Ok(sqlx::query(include_str!("../sql/UsageReport.sql"))
+        .bind(format!("-{days} days"))
+        .bind(query.user_id)
+        .bind(query.guild)
+        .fetch_all(pool)
+        .await
+        .context("Failed to query usage")?
+        .into_iter()
+        .map(|row| UsageRow {
+            day: row.get("day"),
+            user_id: row.get::<Option<String>, _>("user_key").unwrap_or_default(),
+            user: row.get::<Option<String>, _>("username").unwrap_or_default(),
+            endpoint: row.get::<Option<String>, _>("endpoint").unwrap_or_default(),
+            calls: row.get::<i64, _>("calls") as u64,
+            prompt_tokens: row.get::<i64, _>("prompt_tokens") as u64,
+            completion_tokens: row.get::<i64, _>("completion_tokens") as u64,
+            cost: row.get("cost"),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use architectury::prelude::*;
+    use openchad_schemas::UsageQuery;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    #[tokio::test]
+    async fn reports_by_user_and_endpoint() -> Result<()> {
+        // every connection to an in-memory database gets a database of its own
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await?;
+        sqlx::migrate!().run(&pool).await?;
+
+        // ann is renamed to annie partway through
+        for (user, user_id, guild, endpoint, prompt, completion) in [
+            ("ann", "10", "1", "/chat/conversation", 100, 20),
+            ("annie", "10", "1", "/chat/conversation", 300, 80),
+            ("annie", "10", "2", "/chat/research", 1000, 200),
+            ("bob", "11", "1", "/chat/conversation", 50, 10),
+        ] {
+            sqlx::query(include_str!("../sql/UsageInsert.sql"))
+                .bind(user)
+                .bind(user_id)
+                .bind(guild)
+                .bind(endpoint)
+                .bind("responses.conversation")
+                .bind("gpt-3.5-turbo")
+                .bind(prompt)
+                .bind(completion)
+                .bind(false)
+                .bind(super::cost(
+                    "gpt-3.5-turbo",
+                    prompt as u64,
+                    completion as u64,
+                ))
+                .execute(&pool)
+                .await?;
+        }
+
+        let rows = super::report(
+            &pool,
+            UsageQuery {
+                days: None,
+                user_id: Some("10".into()),
+                guild: Some("1".into()),
+            },
+        )
+        .await?;
+
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].endpoint, "/chat/conversation");
+        assert_eq!(rows[0].user_id, "10");
+        assert_eq!(rows[0].calls, 2);
+        assert_eq!(
+            (rows[0].prompt_tokens, rows[0].completion_tokens),
+            (400, 100)
+        );
+        assert!((rows[0].cost - 0.0008).abs() < 1e-9);
+
+        Ok(())
+    }
+}
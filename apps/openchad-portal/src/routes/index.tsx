@@ -11,8 +11,11 @@ export const useGetConfig = routeLoader$(async () => {
 	}
 
 	const url = `http://${apiUrl}/config`;
+	const apiToken = process.env['API_TOKEN'];
 	try {
-		const response = await fetch(url);
+		const response = await fetch(url, {
+			headers: apiToken ? { Authorization: `Bearer ${apiToken}` } : {},
+		});
 		const config: BotConfig = await response.json();
 		return config;
 	} catch (e) {
@@ -0,0 +1,316 @@
+//! A typed client for the OpenChad API, shared by the Discord bot and any other frontend
+
+use std::env::var;
+use std::time::Duration;
+
+use futures::stream::{BoxStream, StreamExt};
+use openchad_schemas::botconfig::BotConfig;
+use openchad_schemas::{
+    AuditBody, AutocompleteBody, AutocompleteResponse, CategorizeBody, CategorizeResponse,
+    ChatBody, ErrorBody, FeedbackBody, HistoryBody, StreamEvent, UsageQuery, UsageRow,
+};
+use reqwest::{Method, RequestBuilder, Response, StatusCode};
+use reqwest_streams::JsonStreamResponse;
+use tracing::{info, warn};
+
+const DEFAULT_RETRIES: u32 = 2;
+const RETRY_BACKOFF: Duration = Duration::from_millis(250);
+// Footers come through in one piece, so this is well over a chunk of the answer
+const MAX_STREAM_ITEM: usize = 64 * 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    /// Over a rate limit or quota, with the API's message saying when to ask again
+    #[error("{0}")]
+    RateLimited(String),
+    #[error("the API answered {status}: {message}")]
+    Status { status: StatusCode, message: String },
+    #[error("couldn't reach the API: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("the answer stream broke off: {0}")]
+    Stream(String),
+    /// Everything up to it came through, but the answer hit the model's length limit
+    #[error("the answer was cut off at the length limit")]
+    Truncated,
+    #[error("API_URL isn't set")]
+    MissingUrl,
+}
+
+impl ClientError {
+    /// Whether the request may go through if it's sent again. A request that isn't `idempotent` is
+    /// only resent when it never reached the API, since an error status may come after the work
+    /// (and the spending) was done
+    fn retryable(&self, idempotent: bool) -> bool {
+        match self {
+            Self::Http(e) => e.is_connect(),
+            Self::Status { status, .. } => {
+                idempotent
+                    && matches!(
+                        *status,
+                        StatusCode::BAD_GATEWAY
+                            | StatusCode::SERVICE_UNAVAILABLE
+                            | StatusCode::GATEWAY_TIMEOUT
+                    )
+            }
+            _ => false,
+        }
+    }
+}
+
+pub type ChatStream = BoxStream<'static, Result<String, ClientError>>;
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    base_url: String,
+    token: Option<String>,
+    timeout: Option<Duration>,
+    retries: u32,
+}
+
+impl Client {
+    /// `base_url` is where the API listens, like `http://localhost:8080`. Without a scheme, it's
+    /// assumed to be `http`
+    pub fn new(base_url: &str) -> Self {
+        let base_url = base_url.trim_end_matches('/');
+        let base_url = if base_url.contains("://") {
+            base_url.to_string()
+        } else {
+            format!("http://{base_url}")
+        };
+
+        Self {
+            http: reqwest::Client::new(),
+            base_url,
+            token: None,
+            timeout: None,
+            retries: DEFAULT_RETRIES,
+        }
+    }
+
+    /// From `API_URL`, `API_SCHEME` (`http` by default) and `API_TOKEN`, which is sent as a bearer
+    /// token when it's set
+    pub fn from_env() -> Result<Self, ClientError> {
+        let url = var("API_URL").map_err(|_| ClientError::MissingUrl)?;
+        let client = match var("API_SCHEME") {
+            Ok(scheme) if !url.contains("://") => Self::new(&format!("{scheme}://{url}")),
+            _ => Self::new(&url),
+        };
+
+        Ok(match var("API_TOKEN") {
+            Ok(token) => client.with_token(token),
+            Err(_) => client,
+        })
+    }
+
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// For the whole request, or until the headers of a streamed answer arrive
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// How many times to resend a request when the API can't be reached, or is unavailable for
+    /// requests that are safe to repeat
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        idempotent: bool,
+        build: impl Fn(RequestBuilder) -> RequestBuilder,
+    ) -> Result<Response, ClientError> {
+        let url = format!("{}{path}", self.base_url);
+        let mut attempt = 0;
+
+        loop {
+            let mut request = self.http.request(method.clone(), &url);
+            if let Some(timeout) = self.timeout {
+                request = request.timeout(timeout);
+            }
+            if let Some(token) = &self.token {
+                request = request.bearer_auth(token);
+            }
+
+            info!("{method} {url}");
+            let result = match build(request).send().await {
+                Ok(response) => check(response).await,
+                Err(e) => Err(e.into()),
+            };
+
+            match result {
+                Err(e) if e.retryable(idempotent) && attempt < self.retries => {
+                    attempt += 1;
+                    warn!(
+                        "{method} {url} failed, retrying ({attempt}/{}): {e}",
+                        self.retries
+                    );
+                    tokio::time::sleep(RETRY_BACKOFF * 2u32.pow(attempt - 1)).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Which endpoint a message is for
+    pub async fn categorize(&self, body: &CategorizeBody) -> Result<String, ClientError> {
+        let response = self
+            .send(Method::GET, "/categorize", false, |request| {
+                request.json(body)
+            })
+            .await?;
+
+        Ok(response.json::<CategorizeResponse>().await?.category)
+    }
+
+    /// The answer from `endpoint` (like `/chat/conversation`), in chunks as they're generated
+    pub async fn chat_stream(
+        &self,
+        endpoint: &str,
+        body: &ChatBody,
+    ) -> Result<ChatStream, ClientError> {
+        let response = self
+            .send(Method::GET, endpoint, false, |request| request.json(body))
+            .await?;
+
+        Ok(response
+            .json_nl_stream::<StreamEvent>(MAX_STREAM_ITEM)
+            .map(|event| match event {
+                Ok(StreamEvent::Chunk(chunk)) => Ok(chunk),
+                Ok(StreamEvent::Error { error }) if error.kind == "truncated" => {
+                    Err(ClientError::Truncated)
+                }
+                Ok(StreamEvent::Error { error }) => Err(ClientError::Stream(error.message)),
+                Err(e) => Err(ClientError::Stream(e.to_string())),
+            })
+            .boxed())
+    }
+
+    /// Adds an answer to the history
+    pub async fn history(&self, body: &HistoryBody) -> Result<(), ClientError> {
+        self.send(Method::POST, "/history", false, |request| {
+            request.json(body)
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn config(&self) -> Result<BotConfig, ClientError> {
+        Ok(self
+            .send(Method::GET, "/config", true, |request| request)
+            .await?
+            .json()
+            .await?)
+    }
+
+    pub async fn update_config(&self, config: &BotConfig) -> Result<(), ClientError> {
+        self.send(Method::POST, "/config", true, |request| {
+            request.json(config)
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn autocomplete(&self, body: &AutocompleteBody) -> Result<Vec<String>, ClientError> {
+        let response = self
+            .send(Method::GET, "/autocomplete", true, |request| {
+                request.json(body)
+            })
+            .await?;
+
+        Ok(response.json::<AutocompleteResponse>().await?.choices)
+    }
+
+    pub async fn feedback(&self, body: &FeedbackBody) -> Result<(), ClientError> {
+        self.send(Method::POST, "/feedback", false, |request| {
+            request.json(body)
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn audit(&self, body: &AuditBody) -> Result<(), ClientError> {
+        self.send(Method::POST, "/audit", false, |request| request.json(body))
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn usage(&self, query: &UsageQuery) -> Result<Vec<UsageRow>, ClientError> {
+        Ok(self
+            .send(Method::GET, "/usage", true, |request| request.query(query))
+            .await?
+            .json()
+            .await?)
+    }
+}
+
+/// Turns an error status into an error, with the message from the body's `ErrorBody`, or the whole
+/// body if it isn't one
+async fn check(response: Response) -> Result<Response, ClientError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let text = response.text().await.unwrap_or_default();
+    let message = match serde_json::from_str::<ErrorBody>(&text) {
+        Ok(body) => body.message,
+        Err(_) => text,
+    };
+
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        Err(ClientError::RateLimited(message))
+    } else {
+        Err(ClientError::Status { status, message })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use architectury::prelude::*;
+
+    use reqwest::StatusCode;
+
+    use super::{Client, ClientError};
+
+    #[test]
+    fn base_url_defaults_to_http() -> Result<()> {
+        assert_eq!(
+            Client::new("localhost:8080").base_url(),
+            "http://localhost:8080"
+        );
+        assert_eq!(
+            Client::new("https://chad.example/api/").base_url(),
+            "https://chad.example/api"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn only_idempotent_requests_retry_error_statuses() {
+        let unavailable = ClientError::Status {
+            status: StatusCode::BAD_GATEWAY,
+            message: "The model is unavailable".into(),
+        };
+
+        assert!(unavailable.retryable(true));
+        assert!(!unavailable.retryable(false));
+    }
+}
@@ -2,6 +2,8 @@ use std::collections::HashMap;
 use std::sync::Arc;
 
 use indexmap::IndexMap;
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{InstanceType, Metadata, Schema, SchemaObject};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
@@ -48,6 +50,56 @@ pub struct ConfigEndpoint {
     pub designation: String,
     pub id: String,
     pub icon: char,
+    pub options: Option<IndexMap<String, ConfigOption>>, // Slash command options, defaults to a required `message`
+    pub access: Option<ConfigAccess>,                    // On top of the global rules
+}
+
+/// Who may use Chad, or an endpoint. Denials win, and every kind of allow list that's set has to
+/// match (a channel also matches the threads in it)
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigAccess {
+    pub allow: Option<ConfigAccessList>,
+    pub deny: Option<ConfigAccessList>,
+    pub message: Option<String>, // Sent when access is denied
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigAccessList {
+    pub guilds: Option<Vec<String>>,
+    pub channels: Option<Vec<String>>,
+    pub roles: Option<Vec<String>>,
+    pub users: Option<Vec<String>>,
+}
+
+/// A slash command option, passed to the task as an arg. The `message` option is also its input
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigOption {
+    pub description: String,
+    pub required: Option<bool>, // Defaults to false
+    #[serde(flatten)]
+    pub kind: ConfigOptionKind,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ConfigOptionKind {
+    String {
+        choices: Option<Vec<String>>,
+        /// `providers.*` task suggesting values as they're typed, one per line of its `choices`
+        /// transform. Can't be combined with `choices`
+        autocomplete: Option<String>,
+    },
+    Integer {
+        choices: Option<Vec<i32>>,
+        min: Option<i64>,
+        max: Option<i64>,
+    },
+    Boolean,
+    /// Also sent along as an attachment, so images work like they do with mentions
+    Attachment,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
@@ -56,6 +108,7 @@ pub struct ConfigResponse {
     pub prompt: Vec<String>,          // Template
     pub transform: Option<Transform>, // Template
     pub footer: Option<String>,       // Template
+    pub images: Option<bool>,         // Send image attachments along, using a vision model
 }
 
 pub type ConfigMacro = IndexMap<String, HashMap<String, String>>;
@@ -66,6 +119,129 @@ pub struct ConfigProvider {
     pub provider: String,
     pub props: HashMap<String, String>,
     pub transform: Transform,
+    pub cache_ttl: Option<u64>, // Seconds
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigThreads {
+    pub guilds: Option<Vec<String>>,   // Every channel in these guilds
+    pub channels: Option<Vec<String>>, // Or only these channels
+    pub auto_archive: Option<AutoArchive>,
+}
+
+/// Minutes of inactivity before a thread is archived. Discord only accepts these durations
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "u16", into = "u16")]
+pub enum AutoArchive {
+    Hour,
+    Day,
+    ThreeDays,
+    Week,
+}
+
+impl AutoArchive {
+    pub const ALL: [Self; 4] = [Self::Hour, Self::Day, Self::ThreeDays, Self::Week];
+
+    pub fn minutes(self) -> u16 {
+        match self {
+            Self::Hour => 60,
+            Self::Day => 1440,
+            Self::ThreeDays => 4320,
+            Self::Week => 10080,
+        }
+    }
+}
+
+impl TryFrom<u16> for AutoArchive {
+    type Error = String;
+
+    fn try_from(minutes: u16) -> Result<Self, Self::Error> {
+        Self::ALL
+            .into_iter()
+            .find(|duration| duration.minutes() == minutes)
+            .ok_or_else(|| format!("threads can't auto archive after {minutes} minutes"))
+    }
+}
+
+impl From<AutoArchive> for u16 {
+    fn from(duration: AutoArchive) -> Self {
+        duration.minutes()
+    }
+}
+
+impl JsonSchema for AutoArchive {
+    fn schema_name() -> String {
+        "AutoArchive".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        SchemaObject {
+            metadata: Some(Box::new(Metadata {
+                description: Some("Minutes of inactivity before a thread is archived".into()),
+                ..Default::default()
+            })),
+            instance_type: Some(InstanceType::Integer.into()),
+            enum_values: Some(Self::ALL.iter().map(|d| d.minutes().into()).collect()),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigContextMenu {
+    pub endpoint: String,    // ID of the endpoint the message is sent to
+    pub history: Option<u8>, // Earlier messages in the channel sent along as context, up to 100
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigDirectMessages {
+    pub enabled: Option<bool>,       // Defaults to false
+    pub guilds: Option<Vec<String>>, // Only answer members of these guilds, anyone if unset
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigLimits {
+    pub user: Option<ConfigLimit>,  // Applied to each user
+    pub guild: Option<ConfigLimit>, // Applied to each guild, shared by everyone in it
+}
+
+/// Requests are limited with a token bucket, and spending is capped per day (UTC)
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigLimit {
+    pub per_minute: Option<f64>, // Requests the bucket refills with every minute, unlimited if unset
+    pub burst: Option<f64>,      // Requests that can be made back to back, defaults to 1
+    pub daily_tokens: Option<u64>, // Prompt and completion tokens
+    pub daily_cost: Option<f64>, // Dollars
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigToolParameter {
+    pub description: String,
+    pub required: Option<bool>, // Defaults to true
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigToolFunction {
+    pub task: String, // `providers.*` task, called with the parameters as args
+    pub description: String,
+    pub parameters: IndexMap<String, ConfigToolParameter>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigTool {
+    pub prompt: Vec<String>,    // Template
+    pub footer: Option<String>, // Template
+    pub functions: IndexMap<String, ConfigToolFunction>,
+    pub max_iterations: Option<usize>, // Rounds of function calls before the model has to answer
 }
 
 config! {
@@ -74,7 +250,16 @@ config! {
     responses: HashMap<String, ConfigResponse>,
     macros: HashMap<String, ConfigMacro>,
     providers: HashMap<String, ConfigProvider>,
+    tools: Option<HashMap<String, ConfigTool>>,
     help_prompt: Vec<String>,       // Template
     categorize_prompt: Vec<String>, // Template
-    message_history: usize
+    message_history: usize,
+    reply_context: Option<usize>, // Replied-to messages sent along with a mention, defaults to 3
+    threads: Option<ConfigThreads>, // Where mentions start a thread for the conversation
+    direct_messages: Option<ConfigDirectMessages>, // Whether and with whom to chat in DMs
+    context_menus: Option<IndexMap<String, ConfigContextMenu>>, // Right-click → Apps commands on messages, by name
+    dev_guilds: Option<Vec<String>>, // Register commands only in these guilds, which is instant, and leave global commands alone
+    access: Option<ConfigAccess>, // Who may use Chad at all
+    limits: Option<ConfigLimits>, // Rate limits and daily quotas, enforced by the API
+    continue_truncated: Option<usize> // Times to ask for the rest of an answer cut off by the length limit, none by default
 }
@@ -1,19 +1,198 @@
-use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug, Default, Clone)]
-#[serde(rename_all = "camelCase")]
+/// Who a message is from, stored by name in `ChatHistory.role`
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatRole {
+    System,
+    #[default]
+    User,
+    Assistant,
+    Tool,
+}
+
+impl ChatRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::System => "system",
+            Self::User => "user",
+            Self::Assistant => "assistant",
+            Self::Tool => "tool",
+        }
+    }
+}
+
+impl fmt::Display for ChatRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ChatRole {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "system" => Ok(Self::System),
+            "user" => Ok(Self::User),
+            "assistant" => Ok(Self::Assistant),
+            "tool" => Ok(Self::Tool),
+            other => Err(format!("Unknown chat role {other:?}")),
+        }
+    }
+}
+
+/// A message in OpenAI's wire format, which is also how history and context are passed around.
+/// Unset fields are left out, so messages from before they existed read and write the same
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
 pub struct ChatMessage {
-    pub role: String,
-    pub content: String,
+    pub role: ChatRole,
+    pub content: ChatContent,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>, // Letters, digits, `_` and `-` only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>, // The call a `tool` message answers
+}
+
+impl ChatMessage {
+    pub fn new(role: ChatRole, content: impl Into<ChatContent>) -> Self {
+        Self {
+            role,
+            content: content.into(),
+            name: None,
+            tool_call_id: None,
+        }
+    }
+
+    /// The result of a tool call, to send back to the model
+    pub fn tool(call_id: impl Into<String>, content: impl Into<ChatContent>) -> Self {
+        Self {
+            tool_call_id: Some(call_id.into()),
+            ..Self::new(ChatRole::Tool, content)
+        }
+    }
 }
 
 impl From<String> for ChatMessage {
     fn from(value: String) -> Self {
-        Self {
-            role: "assistant".into(),
-            content: value,
+        Self::new(ChatRole::Assistant, value)
+    }
+}
+
+/// Plain text, or text and images for vision-capable models. Serializes to OpenAI's wire format.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum ChatContent {
+    Text(String),
+    Parts(Vec<ChatContentPart>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChatContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ChatImageUrl },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChatImageUrl {
+    pub url: String, // Either a link or a `data:` URL
+}
+
+impl Default for ChatContent {
+    fn default() -> Self {
+        Self::Text(String::new())
+    }
+}
+
+impl From<String> for ChatContent {
+    fn from(value: String) -> Self {
+        Self::Text(value)
+    }
+}
+
+impl From<&str> for ChatContent {
+    fn from(value: &str) -> Self {
+        Self::Text(value.into())
+    }
+}
+
+impl ChatContent {
+    /// The text parts, joined by newlines. Images are dropped
+    pub fn text(&self) -> String {
+        match self {
+            Self::Text(text) => text.clone(),
+            Self::Parts(parts) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    ChatContentPart::Text { text } => Some(text.as_str()),
+                    ChatContentPart::ImageUrl { .. } => None,
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+
+    /// `text` followed by every image attachment, or just `text` when there aren't any
+    pub fn with_images(text: String, attachments: &[ChatAttachment]) -> Self {
+        let images = attachments
+            .iter()
+            .filter(|attachment| attachment.is_image())
+            .filter_map(ChatAttachment::image_url)
+            .map(|url| ChatContentPart::ImageUrl {
+                image_url: ChatImageUrl { url },
+            })
+            .collect::<Vec<_>>();
+
+        if images.is_empty() {
+            Self::Text(text)
+        } else {
+            Self::Parts([vec![ChatContentPart::Text { text }], images].concat())
+        }
+    }
+}
+
+/// A file sent along with a chat message, either as a link or inline
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatAttachment {
+    pub url: Option<String>,
+    pub data: Option<String>, // Base64
+    pub content_type: Option<String>,
+    pub filename: Option<String>,
+}
+
+impl ChatAttachment {
+    pub fn is_image(&self) -> bool {
+        match &self.content_type {
+            Some(content_type) => content_type.starts_with("image/"),
+            None => self
+                .filename
+                .as_deref()
+                .or(self.url.as_deref())
+                .and_then(|name| name.split('?').next())
+                .and_then(|name| name.rsplit_once('.'))
+                .is_some_and(|(_, ext)| {
+                    matches!(
+                        ext.to_lowercase().as_str(),
+                        "png" | "jpg" | "jpeg" | "gif" | "webp"
+                    )
+                }),
+        }
+    }
+
+    /// Inline data takes precedence over the link, since links can expire
+    pub fn image_url(&self) -> Option<String> {
+        match (&self.data, &self.url) {
+            (Some(data), _) => Some(format!(
+                "data:{};base64,{data}",
+                self.content_type.as_deref().unwrap_or("image/png")
+            )),
+            (None, Some(url)) => Some(url.clone()),
+            (None, None) => None,
         }
     }
 }
@@ -26,6 +205,10 @@ pub enum FinishReason {
     #[serde(rename = "stop")]
     #[default]
     Stop,
+    #[serde(rename = "tool_calls")]
+    ToolCalls,
+    #[serde(rename = "content_filter")]
+    ContentFilter,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -40,8 +223,8 @@ pub enum ChatCategory {
     Help,
 }
 
+// OpenAI's wire format, so it's left in snake case
 #[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(rename_all = "camelCase")]
 pub struct ChatUsage {
     pub prompt_tokens: u32,
     pub completion_tokens: u32,
@@ -65,10 +248,33 @@ pub struct ChatResponse {
     pub usage: ChatUsage,
 }
 
+// fields other than the content and tool calls, like `role` or `refusal`, can be null
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ChatDelta {
+    pub content: Option<String>,
+    pub tool_calls: Option<Vec<ChatToolCallDelta>>,
+}
+
+/// A piece of a function call. The name and arguments are spread over the deltas with its `index`
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ChatToolCallDelta {
+    pub index: usize,
+    pub id: Option<String>, // Only on the first piece
+    #[serde(default)]
+    pub function: ChatFunctionDelta,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ChatFunctionDelta {
+    pub name: Option<String>,
+    pub arguments: Option<String>,
+}
+
+// `finish_reason` is in OpenAI's snake case too
 #[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(rename_all = "camelCase")]
 pub struct ChatResponseStreamChoice {
-    pub delta: HashMap<String, String>,
+    #[serde(default)]
+    pub delta: ChatDelta,
     pub index: usize,
     pub finish_reason: Option<FinishReason>,
 }
@@ -81,4 +287,19 @@ pub struct ChatResponseStream {
     pub object: String,
     pub created: u64,
     pub model: String,
+    pub usage: Option<ChatUsage>, // Only on the last chunk, with `stream_options.include_usage`
+}
+
+/// What OpenAI answers with instead, as the body of an error status or a line of a stream
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatError {
+    pub error: ChatErrorDetail,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatErrorDetail {
+    pub message: String,
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    pub code: Option<String>,
 }
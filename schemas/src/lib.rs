@@ -3,13 +3,22 @@ pub mod chat;
 pub mod provider;
 pub mod search;
 
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 
+use crate::chat::{ChatAttachment, ChatMessage};
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct CategorizeBody {
     pub message: String,
     pub user: String,
+    pub user_id: Option<String>, // Usage is recorded against it, since names change
+    pub attachments: Option<Vec<ChatAttachment>>,
+    pub context: Option<Vec<ChatMessage>>, // Replied-to messages, oldest first
+    pub scope: Option<String>,             // History key, defaults to `user`
+    pub guild: Option<String>,             // For per-guild limits
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -23,6 +32,13 @@ pub struct CategorizeResponse {
 pub struct ChatBody {
     pub message: String,
     pub user: String,
+    pub user_id: Option<String>, // Usage is recorded against it, since names change
+    pub attachments: Option<Vec<ChatAttachment>>,
+    pub context: Option<Vec<ChatMessage>>, // Replied-to messages, oldest first
+    pub scope: Option<String>,             // History key, defaults to `user`
+    pub args: Option<HashMap<String, String>>, // Slash command options
+    pub guild: Option<String>,             // For per-guild limits
+    pub regenerate: Option<bool>, // Replaces the last exchange in the history instead of adding one
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -36,6 +52,107 @@ pub struct ChatResponse {
 pub struct HistoryBody {
     pub message: String,
     pub user: String,
+    pub scope: Option<String>, // History key, defaults to `user`
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AutocompleteBody {
+    pub task: String,  // The option's `autocomplete` task
+    pub input: String, // What's been typed so far
+    pub user: String,
+    pub user_id: Option<String>,
+    pub guild: Option<String>, // For per-guild limits
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AutocompleteResponse {
+    pub choices: Vec<String>,
+}
+
+/// A 👍 or 👎 on an answer, kept for tuning prompts later
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct FeedbackBody {
+    pub user: String,     // Who rated the answer
+    pub endpoint: String, // Route the answer came from
+    pub message: String,  // The question as sent to the endpoint
+    pub answer: String,
+    pub positive: bool,
+}
+
+/// Someone was turned away by the access rules
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditBody {
+    pub user: String,
+    pub user_id: String,
+    pub guild_id: Option<String>,
+    pub channel_id: String,
+    pub endpoint: Option<String>, // Unset when the global rules denied it
+    pub reason: String,           // The kind of rule that matched: guild, channel, role or user
+}
+
+macro_rules! history_key {
+    ($($body: ty),+) => {
+        $(impl $body {
+            /// History is kept per user, unless the frontend scopes it (to a thread, for example)
+            pub fn history_key(&self) -> &str {
+                self.scope.as_deref().unwrap_or(&self.user)
+            }
+        })+
+    };
+}
+
+history_key!(CategorizeBody, ChatBody, HistoryBody);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ProviderCacheStats {
+    pub storage: String,
+    pub bypass: bool,
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageQuery {
+    pub days: Option<u32>,       // How far back to go, defaults to 7
+    pub user_id: Option<String>, // Only this user's usage
+    pub guild: Option<String>,   // Only this guild's usage
+}
+
+/// Usage for a day, user and endpoint
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageRow {
+    pub day: String,
+    pub user_id: String,
+    pub user: String, // Their name as of their latest call that day
+    pub endpoint: String,
+    pub calls: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub cost: f64, // Dollars
+}
+
+/// What the API answers with when a request fails
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorBody {
+    pub kind: String, // badConfig, llm, truncated, provider, template, rateLimited, notFound, unauthorized or internal
+    pub message: String,
+}
+
+/// A line of a streamed answer: the next chunk of it, or why it stopped partway through
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum StreamEvent {
+    Chunk(String),
+    Error { error: ErrorBody },
 }
 
 #[cfg(test)]
@@ -43,7 +160,7 @@ pub mod tests {
     use architectury::coreutils::*;
     use architectury::prelude::*;
 
-    use crate::botconfig::BotConfig;
+    use crate::botconfig::{AutoArchive, BotConfig};
     use crate::provider::Provider;
 
     #[test]
@@ -55,6 +172,19 @@ pub mod tests {
         Ok(())
     }
 
+    #[test]
+    fn auto_archive_only_takes_discord_durations() {
+        assert_eq!(
+            serde_json::from_str::<AutoArchive>("1440").ok(),
+            Some(AutoArchive::Day)
+        );
+        assert!(serde_json::from_str::<AutoArchive>("1000").is_err());
+        assert_eq!(
+            serde_json::to_string(&AutoArchive::Week).ok().as_deref(),
+            Some("10080")
+        );
+    }
+
     #[test]
     fn generate_bot_schema() -> Result<()> {
         let schema = schemars::schema_for!(BotConfig);
@@ -91,6 +221,41 @@ pub mod tests {
         Ok(())
     }
 
+    #[test]
+    fn chat_messages_keep_wire_format() -> Result<()> {
+        use architectury::prelude::assert_eq;
+        use serde_json::json;
+
+        use crate::chat::{ChatContent, ChatMessage, ChatRole};
+
+        // as stored and sent before roles were typed
+        let message =
+            serde_json::from_value::<ChatMessage>(json!({ "role": "user", "content": "hi" }))?;
+        assert_eq!(message, ChatMessage::new(ChatRole::User, "hi"));
+        assert_eq!(
+            serde_json::to_value(&message)?,
+            json!({ "role": "user", "content": "hi" })
+        );
+
+        assert_eq!(
+            serde_json::to_value(ChatMessage::tool("call_1", "{}"))?,
+            json!({ "role": "tool", "content": "{}", "tool_call_id": "call_1" })
+        );
+
+        let parts = serde_json::from_value::<ChatMessage>(json!({
+            "role": "user",
+            "name": "ann",
+            "content": [{ "type": "text", "text": "what's this?" }],
+        }))?;
+        assert_eq!(parts.name.as_deref(), Some("ann"));
+        assert!(matches!(parts.content, ChatContent::Parts(_)));
+        assert!(
+            serde_json::from_value::<ChatMessage>(json!({ "role": "bot", "content": "" })).is_err()
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn parse_provider_json() -> Result<()> {
         let parsed_cfg = serde_json::from_str::<Provider>(&cat("../providers/bing.json")?);
@@ -11,6 +11,132 @@ pub enum Redirect {
     Headers,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema, PartialEq, Default)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Method {
+    #[default]
+    Get,
+    Post,
+    Put,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum BodyEncoding {
+    #[default]
+    Json,
+    Form,
+    Raw,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum ResponseType {
+    #[default]
+    Json,
+    Text,
+    Xml,
+    Rss,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum StatusAction {
+    /// Abort the task with an error
+    #[default]
+    Fail,
+    /// Continue with a `null` response
+    Empty,
+    /// Parse the response body anyway
+    Passthrough,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct StatusRule {
+    /// Status codes treated as successful. Defaults to any 2xx
+    pub accept: Option<Vec<u16>>,
+    /// What to do when the status code isn't accepted
+    pub on_error: Option<StatusAction>,
+}
+
+/// Every field is a template rendered with the provider's context (including `env`)
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema, PartialEq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ProviderAuth {
+    Bearer {
+        token: String,
+    },
+    Basic {
+        username: String,
+        password: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    QueryParam {
+        name: String,
+        value: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    Oauth2ClientCredentials {
+        token_url: String,
+        client_id: String,
+        client_secret: String,
+        scope: Option<String>,
+    },
+}
+
+/// Providers that run inside the API instead of calling out to a URL
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema, PartialEq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Builtin {
+    /// Ranked search over a directory of Markdown/text files. Reads the `q` and `count` props and
+    /// responds in the same shape as Bing (`webPages.value[].snippet/url`)
+    #[serde(rename_all = "camelCase")]
+    LocalSearch {
+        /// Relative paths are resolved from `PROVIDERS_PATH`
+        path: String,
+        /// Defaults to `md` and `txt`
+        extensions: Option<Vec<String>>,
+        /// Prepended to each file's relative path to build its `url`
+        url_prefix: Option<String>,
+    },
+    /// Runs a query against a read-only SQLite or Postgres database and responds with `{ rows }`
+    #[serde(rename_all = "camelCase")]
+    Sql {
+        /// Connection URL (`sqlite://...` or `postgres://...`). Template with only `env` and `props`
+        database: String,
+        /// Uses `$1`, `$2`, ... placeholders. This is never templated, so input can't end up in
+        /// the SQL itself
+        query: String,
+        /// Props bound to the placeholders, in order. Missing props are bound as `null`
+        params: Vec<String>,
+        /// Defaults to 50
+        max_rows: Option<usize>,
+    },
+    /// Ranks recent entries from a list of RSS/Atom feeds against the `q` prop, responding in
+    /// the same shape as Bing (`webPages.value[].snippet/url`)
+    #[serde(rename_all = "camelCase")]
+    Feeds {
+        urls: Vec<String>,
+        /// Seconds before a feed is fetched again. Defaults to 900
+        refresh: Option<u64>,
+        /// Older entries are ignored. Defaults to 7
+        max_age_days: Option<u64>,
+    },
+    /// Evaluates the `expression` prop locally: arithmetic (`2^10 / 4`), unit conversions
+    /// (`26.2 miles to km`) or date math (`today + 30 days`). Responds with
+    /// `{ kind, expression, result, value }`, or status 422 and `{ error }`
+    Calculator,
+    /// Reads the text in the image from the `data` (base64) or `url` prop with a local
+    /// `tesseract` install. URLs are only fetched over https from Discord's CDN, or the hosts in
+    /// `OCR_IMAGE_HOSTS`. Responds with `{ text }`
+    #[serde(rename_all = "camelCase")]
+    Ocr {
+        /// Tesseract language codes, like `eng+deu`. Defaults to `eng`
+        language: Option<String>,
+    },
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct PropRule {
@@ -22,10 +148,18 @@ pub struct PropRule {
 #[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct Provider {
-    pub url: String,
+    pub url: Option<String>, // Template, required unless `builtin` is set
+    pub builtin: Option<Builtin>,
+    pub method: Option<Method>,
     pub env: Option<Vec<String>>,
+    pub auth: Option<ProviderAuth>,
     pub prop_rules: Vec<PropRule>,
     pub headers: Option<HashMap<String, String>>,
     pub body: Option<HashMap<String, String>>,
+    pub body_encoding: Option<BodyEncoding>,
+    pub raw_body: Option<String>, // Template, used with `bodyEncoding: raw`
+    pub content_type: Option<String>,
     pub query: Option<HashMap<String, String>>,
+    pub response_type: Option<ResponseType>,
+    pub status: Option<StatusRule>,
 }
@@ -0,0 +1,261 @@
+use architectury::prelude::*;
+use openchad_schemas::botconfig::{BotConfig, ConfigAccess, ConfigAccessList, ConfigEndpoint};
+use openchad_schemas::AuditBody;
+use serenity::model::prelude::{Channel, ChannelId, GuildId, RoleId, User};
+use serenity::prelude::*;
+use tokio::spawn;
+
+const DEFAULT_DENIAL: &str = "Sorry, I'm not allowed to help with that here.";
+
+/// Who's asking and where, to check against the access rules
+pub struct Requester {
+    pub user: User,
+    pub guild: Option<GuildId>,
+    pub channels: Vec<ChannelId>, // The channel, followed by its parent if it's a thread
+    pub roles: Vec<RoleId>,
+}
+
+impl Requester {
+    pub async fn new(
+        context: &Context,
+        user: &User,
+        guild: Option<GuildId>,
+        channel: ChannelId,
+        roles: &[RoleId],
+    ) -> Self {
+        let mut channels = vec![channel];
+
+        if let Ok(Channel::Guild(channel)) = channel.to_channel(context).await {
+            channels.extend(channel.parent_id);
+        }
+
+        Self {
+            user: user.clone(),
+            guild,
+            channels,
+            roles: roles.to_vec(),
+        }
+    }
+
+    fn ids(&self, kind: &str) -> Vec<u64> {
+        match kind {
+            "guild" => self.guild.iter().map(|id| id.0).collect(),
+            "channel" => self.channels.iter().map(|id| id.0).collect(),
+            "role" => self.roles.iter().map(|id| id.0).collect(),
+            _ => vec![self.user.id.0],
+        }
+    }
+}
+
+fn lists(list: &ConfigAccessList) -> [(&'static str, &Option<Vec<String>>); 4] {
+    [
+        ("guild", &list.guilds),
+        ("channel", &list.channels),
+        ("role", &list.roles),
+        ("user", &list.users),
+    ]
+}
+
+/// The kind of rule that keeps `requester` out, if any
+fn denied_by(access: &ConfigAccess, requester: &Requester) -> Option<&'static str> {
+    let matches = |kind: &str, ids: &Option<Vec<String>>| {
+        let listed = ids.iter().flatten().collect::<Vec<_>>();
+        requester
+            .ids(kind)
+            .iter()
+            .any(|id| listed.contains(&&id.to_string()))
+    };
+
+    for (kind, ids) in access.deny.iter().flat_map(lists) {
+        if matches(kind, ids) {
+            return Some(kind);
+        }
+    }
+
+    for (kind, ids) in access.allow.iter().flat_map(lists) {
+        if ids.is_some() && !matches(kind, ids) {
+            return Some(kind);
+        }
+    }
+
+    None
+}
+
+/// The first rule that keeps `requester` out
+struct Denial {
+    endpoint: Option<String>, // Unset for the global rules
+    reason: &'static str,
+    message: String,
+}
+
+/// Checks the global rules, then `endpoint`'s
+fn denial(
+    config: &BotConfig,
+    endpoint: Option<&ConfigEndpoint>,
+    requester: &Requester,
+) -> Option<Denial> {
+    let rules = [(None, config.access.as_ref())]
+        .into_iter()
+        .chain(endpoint.map(|endpoint| (Some(endpoint.id.clone()), endpoint.access.as_ref())));
+
+    rules.into_iter().find_map(|(endpoint, access)| {
+        let access = access?;
+        let reason = denied_by(access, requester)?;
+
+        Some(Denial {
+            endpoint,
+            reason,
+            message: access
+                .message
+                .clone()
+                .or_else(|| config.access.as_ref().and_then(|a| a.message.clone()))
+                .unwrap_or_else(|| DEFAULT_DENIAL.into()),
+        })
+    })
+}
+
+/// Whether the rules let `requester` use `endpoint`, without logging or auditing a denial. For
+/// lookups like autocomplete that come in on every keystroke
+pub fn allowed(
+    config: &BotConfig,
+    endpoint: Option<&ConfigEndpoint>,
+    requester: &Requester,
+) -> bool {
+    denial(config, endpoint, requester).is_none()
+}
+
+/// Checks the global rules, then `endpoint`'s. A denial is written to the audit log and comes back
+/// as the message to answer with
+pub fn check(
+    config: &BotConfig,
+    endpoint: Option<&ConfigEndpoint>,
+    requester: &Requester,
+) -> Result<(), String> {
+    let Some(Denial {
+        endpoint,
+        reason,
+        message,
+    }) = denial(config, endpoint, requester)
+    else {
+        return Ok(());
+    };
+
+    warn!(
+        "Denied {} ({}) by {reason} rule{}",
+        requester.user.tag(),
+        requester.user.id,
+        endpoint
+            .as_ref()
+            .map(|endpoint| format!(" for {endpoint}"))
+            .unwrap_or_default()
+    );
+
+    let audit = AuditBody {
+        user: requester.user.name.clone(),
+        user_id: requester.user.id.to_string(),
+        guild_id: requester.guild.map(|id| id.to_string()),
+        channel_id: requester.channels[0].to_string(),
+        endpoint,
+        reason: reason.into(),
+    };
+    spawn(async move {
+        if let Err(e) = crate::API.audit(&audit).await {
+            warn!("Failed to append to the audit log: {e}");
+        }
+    });
+
+    Err(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use openchad_schemas::botconfig::ConfigAccess;
+    use serde_json::json;
+    use serenity::model::prelude::{ChannelId, GuildId, RoleId, User, UserId};
+
+    use super::{denied_by, Requester};
+
+    fn requester(user: u64, channels: &[u64], roles: &[u64]) -> Requester {
+        let mut requester_user = User::default();
+        requester_user.id = UserId(user);
+
+        Requester {
+            user: requester_user,
+            guild: Some(GuildId(1)),
+            channels: channels.iter().copied().map(ChannelId).collect(),
+            roles: roles.iter().copied().map(RoleId).collect(),
+        }
+    }
+
+    #[test]
+    fn denies_before_allowing() {
+        let access = serde_json::from_value::<ConfigAccess>(json!({
+            "allow": { "channels": ["10"], "roles": ["100", "101"] },
+            "deny": { "users": ["7"], "roles": ["666"] },
+        }))
+        .unwrap();
+
+        // (what, requester, the kind of rule that keeps them out)
+        let cases = [
+            (
+                "allowed channel and role",
+                requester(5, &[10], &[100]),
+                None,
+            ),
+            ("any allowed role", requester(5, &[10], &[3, 101]), None),
+            (
+                "thread in an allowed channel",
+                requester(5, &[20, 10], &[100]),
+                None,
+            ),
+            (
+                "other channel",
+                requester(5, &[11], &[100]),
+                Some("channel"),
+            ),
+            (
+                "thread in another channel",
+                requester(5, &[20, 11], &[100]),
+                Some("channel"),
+            ),
+            ("no allowed role", requester(5, &[10], &[3]), Some("role")),
+            ("no roles at all", requester(5, &[10], &[]), Some("role")),
+            ("denied user", requester(7, &[10], &[100]), Some("user")),
+            (
+                "denied role",
+                requester(5, &[10], &[100, 666]),
+                Some("role"),
+            ),
+            (
+                "denied role elsewhere",
+                requester(5, &[11], &[666]),
+                Some("role"),
+            ),
+        ];
+
+        for (what, requester, expected) in cases {
+            assert_eq!(denied_by(&access, &requester), expected, "{what}");
+        }
+    }
+
+    #[test]
+    fn unlisted_kinds_allow_everyone() {
+        let open = serde_json::from_value::<ConfigAccess>(json!({})).unwrap();
+        let guild_only = serde_json::from_value::<ConfigAccess>(json!({
+            "allow": { "guilds": ["1"] },
+            "deny": { "channels": ["13"] },
+        }))
+        .unwrap();
+
+        assert_eq!(denied_by(&open, &requester(5, &[10], &[])), None);
+        assert_eq!(denied_by(&guild_only, &requester(5, &[10], &[])), None);
+        assert_eq!(
+            denied_by(&guild_only, &requester(5, &[20, 13], &[])),
+            Some("channel")
+        );
+
+        let mut outside = requester(5, &[10], &[]);
+        outside.guild = None;
+        assert_eq!(denied_by(&guild_only, &outside), Some("guild"));
+    }
+}
@@ -0,0 +1,663 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use architectury::prelude::*;
+use openchad_schemas::botconfig::{BotConfig, ConfigEndpoint, ConfigOption, ConfigOptionKind};
+use openchad_schemas::chat::ChatAttachment;
+use serde_json::{Number, Value};
+use serenity::builder::{CreateApplicationCommand, CreateApplicationCommands};
+use serenity::http::Http;
+use serenity::model::application::interaction::application_command::CommandData;
+use serenity::model::prelude::command::{Command, CommandOptionType, CommandType};
+use serenity::model::prelude::{AttachmentId, GuildId};
+use serenity::model::Permissions;
+
+/// What gets registered for a command, to compare registered commands with the config
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandSpec {
+    pub name: String,
+    pub kind: CommandType,
+    pub description: String,
+    pub options: Vec<OptionSpec>,
+    pub permissions: Option<Permissions>, // Who can see it by default, everyone if unset
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OptionSpec {
+    pub name: String,
+    pub kind: CommandOptionType,
+    pub description: String,
+    pub required: bool,
+    pub choices: Vec<(String, Value)>,
+    pub min_value: Option<Number>,
+    pub max_value: Option<Number>,
+    pub autocomplete: bool,
+}
+
+impl OptionSpec {
+    fn builtin(name: &str, kind: CommandOptionType, description: &str) -> Self {
+        Self {
+            name: name.into(),
+            kind,
+            description: description.into(),
+            required: false,
+            choices: vec![],
+            min_value: None,
+            max_value: None,
+            autocomplete: false,
+        }
+    }
+
+    fn new(name: &str, option: &ConfigOption) -> Self {
+        let (kind, choices, min, max) = match &option.kind {
+            ConfigOptionKind::String { choices, .. } => (
+                CommandOptionType::String,
+                choices
+                    .iter()
+                    .flatten()
+                    .map(|choice| (choice.clone(), choice.as_str().into()))
+                    .collect(),
+                None,
+                None,
+            ),
+            ConfigOptionKind::Integer { choices, min, max } => (
+                CommandOptionType::Integer,
+                choices
+                    .iter()
+                    .flatten()
+                    .map(|choice| (choice.to_string(), (*choice).into()))
+                    .collect(),
+                *min,
+                *max,
+            ),
+            ConfigOptionKind::Boolean => (CommandOptionType::Boolean, vec![], None, None),
+            ConfigOptionKind::Attachment => (CommandOptionType::Attachment, vec![], None, None),
+        };
+
+        Self {
+            name: name.into(),
+            kind,
+            description: option.description.clone(),
+            required: option.required.unwrap_or_default(),
+            choices,
+            min_value: min.map(Number::from),
+            max_value: max.map(Number::from),
+            autocomplete: matches!(
+                option.kind,
+                ConfigOptionKind::String {
+                    autocomplete: Some(_),
+                    ..
+                }
+            ),
+        }
+    }
+}
+
+impl From<&Command> for CommandSpec {
+    fn from(command: &Command) -> Self {
+        Self {
+            name: command.name.clone(),
+            kind: command.kind,
+            description: command.description.clone(),
+            options: command
+                .options
+                .iter()
+                .map(|option| OptionSpec {
+                    name: option.name.clone(),
+                    kind: option.kind,
+                    description: option.description.clone(),
+                    required: option.required,
+                    choices: option
+                        .choices
+                        .iter()
+                        .map(|choice| (choice.name.clone(), choice.value.clone()))
+                        .collect(),
+                    min_value: option.min_value.clone(),
+                    max_value: option.max_value.clone(),
+                    autocomplete: option.autocomplete,
+                })
+                .collect(),
+            permissions: command.default_member_permissions,
+        }
+    }
+}
+
+impl CommandSpec {
+    fn build<'a>(
+        &self,
+        command: &'a mut CreateApplicationCommand,
+    ) -> &'a mut CreateApplicationCommand {
+        command.name(&self.name).kind(self.kind);
+
+        // context menu commands can't have a description
+        if self.kind == CommandType::ChatInput {
+            command.description(&self.description);
+        }
+
+        // commands for admins only make sense in a guild
+        if let Some(permissions) = self.permissions {
+            command
+                .default_member_permissions(permissions)
+                .dm_permission(false);
+        }
+
+        for option in &self.options {
+            command.create_option(|builder| {
+                builder
+                    .name(&option.name)
+                    .description(&option.description)
+                    .kind(option.kind)
+                    .required(option.required)
+                    .set_autocomplete(option.autocomplete);
+
+                for (name, value) in &option.choices {
+                    match value.as_i64() {
+                        Some(value) => builder.add_int_choice(name, value as i32),
+                        None => builder.add_string_choice(name, value.as_str().unwrap_or(name)),
+                    };
+                }
+                if let Some(min) = &option.min_value {
+                    builder.min_int_value(min.clone());
+                }
+                if let Some(max) = &option.max_value {
+                    builder.max_int_value(max.clone());
+                }
+
+                builder
+            });
+        }
+
+        command
+    }
+}
+
+/// Every command `config` calls for: one per endpoint, the context menus, /help and /usage
+pub fn desired_commands(config: &BotConfig) -> Vec<CommandSpec> {
+    let mut commands = config
+        .endpoints
+        .values()
+        .map(|endpoint| CommandSpec {
+            name: endpoint.id.to_lowercase(),
+            kind: CommandType::ChatInput,
+            description: endpoint.designation.clone(),
+            options: endpoint_options(endpoint)
+                .iter()
+                .map(|(name, option)| OptionSpec::new(name, option))
+                .collect(),
+            permissions: None,
+        })
+        .collect::<Vec<_>>();
+
+    commands.extend(
+        config
+            .context_menus
+            .iter()
+            .flat_map(|menus| menus.keys())
+            .map(|name| CommandSpec {
+                name: name.clone(),
+                kind: CommandType::Message,
+                description: String::new(),
+                options: vec![],
+                permissions: None,
+            }),
+    );
+
+    commands.push(CommandSpec {
+        name: "help".into(),
+        kind: CommandType::ChatInput,
+        description: "Show a full list of features".into(),
+        options: vec![],
+        permissions: None,
+    });
+
+    commands.push(CommandSpec {
+        name: "usage".into(),
+        kind: CommandType::ChatInput,
+        description: "Show what Chad has cost in this server".into(),
+        options: vec![
+            OptionSpec {
+                min_value: Some(1.into()),
+                max_value: Some(90.into()),
+                ..OptionSpec::builtin(
+                    "days",
+                    CommandOptionType::Integer,
+                    "How many days back to go, 7 by default",
+                )
+            },
+            OptionSpec::builtin("user", CommandOptionType::User, "Only this user's usage"),
+        ],
+        permissions: Some(Permissions::ADMINISTRATOR),
+    });
+
+    commands
+}
+
+/// The options an endpoint's command takes, which is just a `message` unless it says otherwise
+pub fn endpoint_options(endpoint: &ConfigEndpoint) -> Vec<(String, ConfigOption)> {
+    match &endpoint.options {
+        Some(options) => options.clone().into_iter().collect(),
+        None => vec![(
+            "message".into(),
+            ConfigOption {
+                description: "The message to relay".into(),
+                required: Some(true),
+                kind: ConfigOptionKind::String {
+                    choices: None,
+                    autocomplete: None,
+                },
+            },
+        )],
+    }
+}
+
+/// Options Discord would refuse to register, found when the config loads rather than on sync
+#[derive(Debug)]
+pub struct OptionErrors(pub Vec<String>);
+
+impl fmt::Display for OptionErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Found {} command option error(s)", self.0.len())?;
+        for error in &self.0 {
+            write!(f, "\n  - {error}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for OptionErrors {}
+
+/// Checks that required options come before optional ones and that no option has both choices
+/// and autocomplete, since Discord rejects the whole bulk overwrite for either
+pub fn check_options(config: &BotConfig) -> Result<(), OptionErrors> {
+    let mut errors = vec![];
+
+    for (url, endpoint) in &config.endpoints {
+        let mut optional = None;
+
+        for (name, option) in endpoint_options(endpoint) {
+            if !option.required.unwrap_or_default() {
+                optional.get_or_insert_with(|| name.clone());
+            } else if let Some(optional) = &optional {
+                errors.push(format!(
+                    "`{url}` option `{name}` is required, but comes after optional `{optional}`"
+                ));
+            }
+
+            if let ConfigOptionKind::String {
+                choices: Some(_),
+                autocomplete: Some(_),
+            } = option.kind
+            {
+                errors.push(format!(
+                    "`{url}` option `{name}` can't have both `choices` and `autocomplete`"
+                ));
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(OptionErrors(errors))
+    }
+}
+
+/// What a slash command was given, checked against its endpoint's options
+pub struct CommandInput {
+    pub message: String,
+    pub args: HashMap<String, String>,
+    pub attachments: Vec<ChatAttachment>,
+}
+
+impl CommandInput {
+    /// Checks `data`'s options, with errors worded for whoever used the command. Discord checks
+    /// most of this too, but the registered command can lag behind the config
+    pub fn parse(endpoint: &ConfigEndpoint, data: &CommandData) -> Result<Self, String> {
+        let mut args = HashMap::new();
+        let mut attachments = vec![];
+
+        for (name, option) in endpoint_options(endpoint) {
+            let given = data
+                .options
+                .iter()
+                .find(|given| given.name == name)
+                .and_then(|given| given.value.as_ref());
+
+            let Some(value) = given else {
+                if option.required.unwrap_or_default() {
+                    return Err(format!("`{name}` is required."));
+                }
+                continue;
+            };
+
+            let value = match &option.kind {
+                ConfigOptionKind::String { choices, .. } => {
+                    let value = value
+                        .as_str()
+                        .ok_or_else(|| format!("`{name}` should be text."))?;
+
+                    if let Some(choices) =
+                        choices.as_ref().filter(|c| !c.iter().any(|c| c == value))
+                    {
+                        return Err(format!(
+                            "`{name}` should be one of: {}.",
+                            choices.join(", ")
+                        ));
+                    }
+
+                    value.to_string()
+                }
+                ConfigOptionKind::Integer { choices, min, max } => {
+                    let value = value
+                        .as_i64()
+                        .ok_or_else(|| format!("`{name}` should be a whole number."))?;
+
+                    if let Some(choices) = choices
+                        .as_ref()
+                        .filter(|c| !c.iter().any(|c| *c as i64 == value))
+                    {
+                        let choices = choices.iter().map(i32::to_string).collect::<Vec<_>>();
+                        return Err(format!(
+                            "`{name}` should be one of: {}.",
+                            choices.join(", ")
+                        ));
+                    }
+                    if min.is_some_and(|min| value < min) || max.is_some_and(|max| value > max) {
+                        return Err(format!(
+                            "`{name}` should be between {} and {}.",
+                            min.map_or("-∞".into(), |min| min.to_string()),
+                            max.map_or("∞".into(), |max| max.to_string())
+                        ));
+                    }
+
+                    value.to_string()
+                }
+                ConfigOptionKind::Boolean => value
+                    .as_bool()
+                    .ok_or_else(|| format!("`{name}` should be true or false."))?
+                    .to_string(),
+                ConfigOptionKind::Attachment => {
+                    let attachment = value
+                        .as_str()
+                        .and_then(|id| id.parse().ok())
+                        .and_then(|id| data.resolved.attachments.get(&AttachmentId(id)))
+                        .ok_or_else(|| format!("`{name}` should be a file."))?;
+
+                    attachments.push(ChatAttachment {
+                        url: Some(attachment.url.clone()),
+                        data: None,
+                        content_type: attachment.content_type.clone(),
+                        filename: Some(attachment.filename.clone()),
+                    });
+
+                    attachment.url.clone()
+                }
+            };
+
+            args.insert(name, value);
+        }
+
+        Ok(Self {
+            message: args.get("message").cloned().unwrap_or_default(),
+            args,
+            attachments,
+        })
+    }
+
+    /// How the input is shown above the answer
+    pub fn summary(&self) -> String {
+        if !self.message.is_empty() {
+            return self.message.clone();
+        }
+
+        let mut args = self
+            .args
+            .iter()
+            .map(|(name, value)| format!("{name}: {value}"))
+            .collect::<Vec<_>>();
+        args.sort();
+        args.join(", ")
+    }
+}
+
+fn dev_guilds(config: &BotConfig) -> Vec<GuildId> {
+    config
+        .dev_guilds
+        .iter()
+        .flatten()
+        .filter_map(|guild| match guild.parse() {
+            Ok(id) => Some(GuildId(id)),
+            Err(_) => {
+                warn!("devGuilds has an invalid guild id: {guild}");
+                None
+            }
+        })
+        .collect()
+}
+
+/// Makes the registered commands match `config`, overwriting them in bulk only where they differ.
+/// With `devGuilds` set, commands are registered in those guilds (which is instant) instead of
+/// globally (which takes up to an hour), and guilds dropped from it since `previous` are cleared.
+/// Global commands are left alone while `devGuilds` is set, so a dev bot sharing the production
+/// application doesn't wipe them
+pub async fn sync_commands(
+    http: &Http,
+    config: &BotConfig,
+    previous: Option<&BotConfig>,
+) -> Result<()> {
+    let desired = desired_commands(config);
+    let guilds = dev_guilds(config);
+
+    if guilds.is_empty() {
+        sync(http, None, &desired).await?;
+    }
+
+    for guild in &guilds {
+        sync(http, Some(*guild), &desired).await?;
+    }
+
+    for guild in previous
+        .map(dev_guilds)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|guild| !guilds.contains(guild))
+    {
+        sync(http, Some(guild), &[]).await?;
+    }
+
+    Ok(())
+}
+
+async fn sync(http: &Http, guild: Option<GuildId>, desired: &[CommandSpec]) -> Result<()> {
+    let registered = match guild {
+        Some(guild) => guild.get_application_commands(http).await?,
+        None => Command::get_global_application_commands(http).await?,
+    };
+    let registered = registered.iter().map(CommandSpec::from).collect::<Vec<_>>();
+    let scope = guild.map_or("globally".into(), |guild| format!("in guild {guild}"));
+
+    if up_to_date(&registered, desired) {
+        info!("Commands registered {scope} are up to date");
+        return Ok(());
+    }
+
+    match guild {
+        Some(guild) => {
+            guild
+                .set_application_commands(http, |commands| build_all(commands, desired))
+                .await?;
+        }
+        None => {
+            Command::set_global_application_commands(http, |commands| build_all(commands, desired))
+                .await?;
+        }
+    }
+
+    info!(
+        "Registered {} command(s) {scope}, replacing {}",
+        desired.len(),
+        registered.len()
+    );
+
+    Ok(())
+}
+
+/// Whether `registered` already has exactly the `desired` commands, in any order
+fn up_to_date(registered: &[CommandSpec], desired: &[CommandSpec]) -> bool {
+    registered.len() == desired.len() && desired.iter().all(|spec| registered.contains(spec))
+}
+
+fn build_all<'a>(
+    commands: &'a mut CreateApplicationCommands,
+    desired: &[CommandSpec],
+) -> &'a mut CreateApplicationCommands {
+    for spec in desired {
+        commands.create_application_command(|command| spec.build(command));
+    }
+
+    commands
+}
+
+#[cfg(test)]
+mod tests {
+    use openchad_schemas::botconfig::BotConfig;
+    use serde_json::{json, Value};
+    use serenity::model::application::interaction::application_command::CommandData;
+
+    use super::{check_options, desired_commands, up_to_date, CommandInput};
+
+    fn config(options: Value) -> BotConfig {
+        serde_json::from_value(json!({
+            "endpoints": {
+                "/ask": {
+                    "task": "ask",
+                    "categorization": "Questions",
+                    "designation": "Ask a question",
+                    "id": "ask",
+                    "icon": "❓",
+                    "options": options,
+                },
+            },
+            "fallbackEndpoint": "/ask",
+            "props": {},
+            "responses": {},
+            "macros": {},
+            "providers": {},
+            "helpPrompt": [],
+            "categorizePrompt": [],
+            "messageHistory": 10,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn checks_options_on_load() {
+        let valid = config(json!({
+            "question": { "description": "What to ask", "required": true, "type": "string" },
+            "tone": { "description": "How", "type": "string", "choices": ["dry", "warm"] },
+            "topic": { "description": "About", "type": "string", "autocomplete": "providers.topics" },
+        }));
+        assert!(check_options(&valid).is_ok());
+
+        let misordered = config(json!({
+            "mood": { "description": "How", "type": "string" },
+            "question": { "description": "What to ask", "required": true, "type": "string" },
+        }));
+        let errors = check_options(&misordered).unwrap_err().0;
+        assert_eq!(
+            errors,
+            ["`/ask` option `question` is required, but comes after optional `mood`"]
+        );
+
+        let both = config(json!({
+            "topic": {
+                "description": "About",
+                "type": "string",
+                "choices": ["rust"],
+                "autocomplete": "providers.topics",
+            },
+        }));
+        let errors = check_options(&both).unwrap_err().0;
+        assert_eq!(
+            errors,
+            ["`/ask` option `topic` can't have both `choices` and `autocomplete`"]
+        );
+    }
+
+    #[test]
+    fn diffs_commands_ignoring_order() {
+        let desired = desired_commands(&config(json!({
+            "question": { "description": "What to ask", "required": true, "type": "string" },
+        })));
+
+        let mut reordered = desired.clone();
+        reordered.reverse();
+        assert!(up_to_date(&reordered, &desired));
+
+        let changed = desired_commands(&config(json!({
+            "question": { "description": "What to ask", "required": false, "type": "string" },
+        })));
+        assert!(!up_to_date(&changed, &desired));
+        assert!(!up_to_date(&desired[1..], &desired));
+    }
+
+    fn parse(given: Value) -> Result<CommandInput, String> {
+        let config = config(json!({
+            "question": { "description": "What to ask", "required": true, "type": "string" },
+            "tone": { "description": "How", "type": "string", "choices": ["dry", "warm"] },
+            "words": { "description": "How long", "type": "integer", "min": 10, "max": 500 },
+        }));
+        let data = serde_json::from_value::<CommandData>(json!({
+            "id": "1",
+            "name": "ask",
+            "type": 1,
+            "options": given,
+        }))
+        .unwrap();
+
+        CommandInput::parse(&config.endpoints["/ask"], &data)
+    }
+
+    #[test]
+    fn parses_command_options() {
+        let input = parse(json!([
+            { "name": "question", "type": 3, "value": "Why?" },
+            { "name": "tone", "type": 3, "value": "dry" },
+            { "name": "words", "type": 4, "value": 50 },
+        ]))
+        .unwrap();
+        assert_eq!(input.message, "");
+        assert_eq!(input.args["question"], "Why?");
+        assert_eq!(input.args["tone"], "dry");
+        assert_eq!(input.args["words"], "50");
+
+        // (what, given options, what the user is told)
+        let cases = [
+            (
+                "out of range",
+                json!([
+                    { "name": "question", "type": 3, "value": "Why?" },
+                    { "name": "words", "type": 4, "value": 9000 },
+                ]),
+                "`words` should be between 10 and 500.",
+            ),
+            (
+                "invalid choice",
+                json!([
+                    { "name": "question", "type": 3, "value": "Why?" },
+                    { "name": "tone", "type": 3, "value": "loud" },
+                ]),
+                "`tone` should be one of: dry, warm.",
+            ),
+            (
+                "missing required",
+                json!([{ "name": "tone", "type": 3, "value": "warm" }]),
+                "`question` is required.",
+            ),
+        ];
+
+        for (what, given, expected) in cases {
+            assert_eq!(parse(given).err().as_deref(), Some(expected), "{what}");
+        }
+    }
+}
@@ -1,35 +1,148 @@
-use std::collections::HashMap;
+mod access;
+mod commands;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env::var;
 use std::process::exit;
 use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use architectury::coreutils::cat;
 use architectury::prelude::*;
 use once_cell::sync::Lazy;
+use openchad_client::ClientError;
+use openchad_schemas::botconfig::AutoArchive;
 use openchad_schemas::botconfig::BotConfig;
-use openchad_schemas::{CategorizeBody, CategorizeResponse, ChatBody, HistoryBody};
-use reqwest_streams::JsonStreamResponse;
-use serenity::futures::StreamExt;
+use openchad_schemas::botconfig::ConfigOptionKind;
+use openchad_schemas::chat::{ChatAttachment, ChatMessage, ChatRole};
+use openchad_schemas::{
+    AutocompleteBody, CategorizeBody, ChatBody, FeedbackBody, HistoryBody, UsageQuery,
+};
+use serenity::builder::CreateComponents;
+use serenity::futures::{Stream, StreamExt};
+use serenity::http::Http;
+use serenity::model::application::component::ButtonStyle;
+use serenity::model::application::interaction::application_command::ApplicationCommandInteraction;
+use serenity::model::application::interaction::autocomplete::AutocompleteInteraction;
+use serenity::model::application::interaction::message_component::MessageComponentInteraction;
 use serenity::model::application::interaction::{
     Interaction, InteractionResponseType, MessageFlags,
 };
-use serenity::model::prelude::command::{Command, CommandOptionType};
-use serenity::model::prelude::{Activity, Message, Ready};
+use serenity::model::prelude::command::CommandType;
+use serenity::model::prelude::{
+    Activity, Channel, ChannelId, ChannelType, GuildChannel, GuildId, Message, MessageId,
+    MessageReference, Ready, User, UserId,
+};
 use serenity::{async_trait, prelude::*};
-use tap::Tap;
 use tokio::spawn;
+use tokio::sync::Notify;
+
+use crate::access::Requester;
+use crate::commands::CommandInput;
 
 static mut EDIT_MAP: Lazy<HashMap<u64, Arc<std::sync::Mutex<String>>>> =
     Lazy::new(|| HashMap::new());
 static EDIT_INDEX: AtomicU64 = AtomicU64::new(0);
 
+static API: Lazy<openchad_client::Client> =
+    Lazy::new(|| openchad_client::Client::from_env().unwrap());
+
+/// Threads Chad holds conversations in, which don't need a mention to get a reply
+static THREADS: Lazy<std::sync::Mutex<HashSet<ChannelId>>> = Lazy::new(Default::default);
+
+/// What an answer was asked, so the buttons under it can stop, regenerate or rate it
+#[derive(Clone)]
+struct Exchange {
+    endpoint: String,
+    body: ChatBody,
+    header: String, // Shown above the answer, like the command that was used
+    asker: UserId,  // The only one who can press the buttons
+    stop: Arc<Notify>,
+}
+
+impl Exchange {
+    fn new(endpoint: &str, body: ChatBody, header: String, asker: UserId) -> Self {
+        Self {
+            endpoint: endpoint.into(),
+            body,
+            header,
+            asker,
+            stop: Default::default(),
+        }
+    }
+}
+
+/// When suggestions were fetched and what they were, by task and input
+type SuggestionCache = HashMap<(String, String), (Instant, Vec<String>)>;
+
+/// Recent autocomplete suggestions, since every keystroke asks for them again
+static SUGGESTIONS: Lazy<std::sync::Mutex<SuggestionCache>> = Lazy::new(Default::default);
+
+/// Exchanges by answer, oldest first since message ids grow over time
+static EXCHANGES: Lazy<std::sync::Mutex<BTreeMap<MessageId, Exchange>>> =
+    Lazy::new(Default::default);
+
+/// Answers whose buttons still work, older ones are forgotten
+const MAX_EXCHANGES: usize = 1000;
+const CONFIG_POLL: Duration = Duration::from_secs(10);
+// Discord gives up on autocomplete after 3 seconds
+const AUTOCOMPLETE_TIMEOUT: Duration = Duration::from_millis(2500);
+const SUGGESTION_TTL: Duration = Duration::from_secs(60);
+const MAX_SUGGESTIONS: usize = 500;
+const DEFAULT_REPLY_CONTEXT: usize = 3;
+const DEFAULT_AUTO_ARCHIVE: AutoArchive = AutoArchive::Hour;
+const USAGE_REPORT_LENGTH: usize = 1900;
+const CATEGORIZE_TIMEOUT: Duration = Duration::from_secs(2);
+// Until the answer starts streaming in
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(20);
+const UNAVAILABLE: &str = "I can't answer right now, please try again later.";
+
 pub fn read_config() -> Result<BotConfig> {
-    Ok(serde_json::from_str(&cat(var("CONFIG_PATH")?)?)?)
+    let config = serde_json::from_str(&cat(var("CONFIG_PATH")?)?)?;
+    commands::check_options(&config)?;
+    Ok(config)
+}
+
+static CONFIG: Lazy<std::sync::RwLock<Arc<BotConfig>>> =
+    Lazy::new(|| std::sync::RwLock::new(Arc::new(read_config().unwrap())));
+
+/// The config that's currently in use
+fn config() -> Arc<BotConfig> {
+    CONFIG.read().unwrap().clone()
 }
 
-static CONFIG: Lazy<Arc<BotConfig>> = Lazy::new(|| Arc::new(read_config().unwrap()));
+/// Reloads the config whenever its file changes (when it's saved from the portal, for example) and
+/// syncs commands with it
+async fn watch_config(http: Arc<Http>) {
+    let path = var("CONFIG_PATH").unwrap();
+    let modified = || std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+    let mut last = modified();
+    let mut interval = tokio::time::interval(CONFIG_POLL);
+
+    loop {
+        interval.tick().await;
+
+        let current = modified();
+        if current == last {
+            continue;
+        }
+        last = current;
+
+        match read_config() {
+            Ok(new) => {
+                let previous = config();
+                *CONFIG.write().unwrap() = Arc::new(new);
+                info!("Reloaded config from {path}");
+
+                if let Err(e) = commands::sync_commands(&http, &config(), Some(&previous)).await {
+                    error!("Failed to sync commands: {e}");
+                }
+            }
+            Err(e) => warn!("Keeping the current config, {path} is invalid: {e}"),
+        }
+    }
+}
 
 struct Handler;
 
@@ -40,10 +153,777 @@ fn remove_mentions<S: AsRef<str>>(msg: S, context: &Context) -> String {
         .into()
 }
 
+/// The messages `msg` replies to, followed by the starter message of the thread it's in, as prior
+/// turns (oldest first) so the API knows what "this" refers to
+async fn reply_context(context: &Context, msg: &Message) -> Vec<ChatMessage> {
+    let depth = config().reply_context.unwrap_or(DEFAULT_REPLY_CONTEXT);
+    let mut chain: Vec<Message> = vec![];
+    let mut next = msg.referenced_message.as_deref().cloned();
+
+    while chain.len() < depth {
+        let Some(message) = next.take() else {
+            break;
+        };
+
+        // only the first hop comes with the event, the rest have to be fetched
+        next = match &message.message_reference {
+            Some(MessageReference {
+                message_id: Some(id),
+                channel_id,
+                ..
+            }) => channel_id.message(&context.http, *id).await.ok(),
+            _ => None,
+        };
+
+        chain.push(message);
+    }
+
+    if chain.len() < depth {
+        if let Ok(Channel::Guild(channel)) = msg.channel_id.to_channel(context).await {
+            if is_thread(&channel) {
+                // a thread started from a message shares its id, forum posts keep it inside
+                let starter = match channel.parent_id {
+                    Some(parent) => parent
+                        .message(&context.http, MessageId(channel.id.0))
+                        .await
+                        .ok(),
+                    None => None,
+                };
+                let starter = match starter {
+                    Some(starter) => Some(starter),
+                    None => channel
+                        .id
+                        .message(&context.http, MessageId(channel.id.0))
+                        .await
+                        .ok(),
+                };
+
+                if let Some(starter) = starter.filter(|starter| {
+                    starter.id != msg.id && chain.iter().all(|m| m.id != starter.id)
+                }) {
+                    chain.push(starter);
+                }
+            }
+        }
+    }
+
+    chain
+        .into_iter()
+        .rev()
+        .map(|message| chat_message(context, &message))
+        .collect()
+}
+
+/// `message` as a prior turn, from the assistant if Chad sent it
+fn chat_message(context: &Context, message: &Message) -> ChatMessage {
+    let content = remove_mentions(message.content_safe(context), context);
+
+    if message.author.id == context.cache.current_user_id() {
+        ChatMessage::new(ChatRole::Assistant, content)
+    } else {
+        ChatMessage::new(
+            ChatRole::User,
+            format!("{}: {content}", message.author.name),
+        )
+    }
+}
+
+fn image_attachments(msg: &Message) -> Vec<ChatAttachment> {
+    msg.attachments
+        .iter()
+        .map(|attachment| ChatAttachment {
+            url: Some(attachment.url.clone()),
+            data: None,
+            content_type: attachment.content_type.clone(),
+            filename: Some(attachment.filename.clone()),
+        })
+        .filter(ChatAttachment::is_image)
+        .collect()
+}
+
+fn is_thread(channel: &GuildChannel) -> bool {
+    matches!(
+        channel.kind,
+        ChannelType::PublicThread | ChannelType::PrivateThread | ChannelType::NewsThread
+    )
+}
+
+/// Whether a mention in `channel_id` should start a thread for the conversation
+fn threads_enabled(guild_id: Option<GuildId>, channel_id: ChannelId) -> bool {
+    let config = config();
+    let Some(threads) = &config.threads else {
+        return false;
+    };
+    let listed = |ids: &Option<Vec<String>>, id: u64| {
+        ids.iter().flatten().any(|listed| listed == &id.to_string())
+    };
+
+    guild_id.is_some_and(|guild_id| listed(&threads.guilds, guild_id.0))
+        || listed(&threads.channels, channel_id.0)
+}
+
+/// The thread `msg` was sent in, if Chad holds a conversation there. Mentioning Chad in a thread
+/// of a channel with threads enabled picks it (back) up, e.g. after a restart
+async fn conversation_thread(
+    context: &Context,
+    msg: &Message,
+    mentioned: bool,
+) -> Option<ChannelId> {
+    if THREADS.lock().unwrap().contains(&msg.channel_id) {
+        return Some(msg.channel_id);
+    }
+    if !mentioned {
+        return None;
+    }
+
+    let Ok(Channel::Guild(channel)) = msg.channel_id.to_channel(context).await else {
+        return None;
+    };
+    let parent = channel.parent_id?;
+
+    if is_thread(&channel) && threads_enabled(msg.guild_id, parent) {
+        THREADS.lock().unwrap().insert(channel.id);
+        Some(channel.id)
+    } else {
+        None
+    }
+}
+
+/// Whether `msg` is a DM Chad should answer, as configured in `directMessages`
+async fn dm_allowed(context: &Context, msg: &Message) -> bool {
+    let config = config();
+    let Some(dms) = config
+        .direct_messages
+        .as_ref()
+        .filter(|dms| dms.enabled.unwrap_or_default())
+    else {
+        return false;
+    };
+    let Some(guilds) = &dms.guilds else {
+        return true;
+    };
+
+    for guild in guilds {
+        let Ok(guild) = guild.parse().map(GuildId) else {
+            warn!("directMessages.guilds has an invalid guild id: {guild}");
+            continue;
+        };
+        if guild.member(context, msg.author.id).await.is_ok() {
+            return true;
+        }
+    }
+
+    info!(
+        "Ignoring DM from {}, who isn't in an allowed guild",
+        msg.author.tag()
+    );
+    false
+}
+
+/// Starts a thread on `msg` named after the question, for the rest of the conversation
+async fn start_thread(context: &Context, msg: &Message, question: &str) -> Option<ChannelId> {
+    let name = match question.lines().next().map(str::trim) {
+        Some(line) if !line.is_empty() => line.chars().take(100).collect::<String>(),
+        _ => format!("Chat with {}", context.cache.current_user().name),
+    };
+    let archive = config()
+        .threads
+        .as_ref()
+        .and_then(|threads| threads.auto_archive)
+        .unwrap_or(DEFAULT_AUTO_ARCHIVE);
+
+    match msg
+        .channel_id
+        .create_public_thread(&context.http, msg.id, |thread| {
+            thread.name(name).auto_archive_duration(archive.minutes())
+        })
+        .await
+    {
+        Ok(thread) => {
+            THREADS.lock().unwrap().insert(thread.id);
+            Some(thread.id)
+        }
+        Err(e) => {
+            warn!("Failed to start a thread in {}: {e}", msg.channel_id);
+            None
+        }
+    }
+}
+
+fn answer_buttons(components: &mut CreateComponents, streaming: bool) -> &mut CreateComponents {
+    components.create_action_row(|row| {
+        if streaming {
+            row.create_button(|button| {
+                button
+                    .custom_id("stop")
+                    .emoji('⏹')
+                    .label("Stop")
+                    .style(ButtonStyle::Secondary)
+            })
+        } else {
+            row.create_button(|button| {
+                button
+                    .custom_id("regenerate")
+                    .emoji('🔁')
+                    .label("Regenerate")
+                    .style(ButtonStyle::Secondary)
+            })
+            .create_button(|button| {
+                button
+                    .custom_id("feedback:up")
+                    .emoji('👍')
+                    .style(ButtonStyle::Secondary)
+            })
+            .create_button(|button| {
+                button
+                    .custom_id("feedback:down")
+                    .emoji('👎')
+                    .style(ButtonStyle::Secondary)
+            })
+        }
+    })
+}
+
+/// Streams an answer into `reply` under a Stop button, then adds it to the history and swaps in
+/// Regenerate and feedback buttons. Stopping drops the response, which cancels the API's request too.
+/// If the answer fails or is cut off, that's noted under what came through.
+async fn relay(
+    context: &Context,
+    mut reply: Message,
+    stream: impl Stream<Item = Result<String, ClientError>>,
+    exchange: Exchange,
+) {
+    let stop = exchange.stop.clone();
+    let Exchange {
+        endpoint,
+        body,
+        header,
+        ..
+    } = exchange.clone();
+
+    {
+        let mut exchanges = EXCHANGES.lock().unwrap();
+        exchanges.insert(reply.id, exchange);
+        while exchanges.len() > MAX_EXCHANGES {
+            exchanges.pop_first();
+        }
+    }
+
+    reply
+        .edit(context, |m| {
+            m.content(format!("{header}..."))
+                .components(|components| answer_buttons(components, true))
+        })
+        .await
+        .unwrap();
+
+    let edit_index = EDIT_INDEX.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    let mut stream = std::pin::pin!(stream.take_until(async move { stop.notified().await }));
+    let mut failure = None;
+
+    while let Some(content) = stream.next().await {
+        let content = match content {
+            Ok(content) => content,
+            Err(e) => {
+                failure = Some(e);
+                break;
+            }
+        };
+        let msg_contents = unsafe { EDIT_MAP.entry(edit_index).or_default() };
+
+        {
+            let mut msg_contents = msg_contents.lock().unwrap();
+            msg_contents.push_str(&content);
+        }
+
+        let content = format!("{header}{}", msg_contents.lock().unwrap());
+        reply
+            .clone()
+            .edit(context, |m| m.content(content))
+            .await
+            .unwrap();
+    }
+
+    // nothing is left to edit in when it's stopped or fails before the first token
+    let m = unsafe { EDIT_MAP.remove(&edit_index) }
+        .map(|m| Arc::try_unwrap(m).unwrap().into_inner().unwrap())
+        .unwrap_or_default();
+
+    let notice = match failure {
+        None if m.is_empty() => Some("*Stopped*".to_string()),
+        None => None,
+        Some(ClientError::Truncated) => {
+            Some("*The answer was cut off at the length limit.*".into())
+        }
+        Some(e) => {
+            warn!("An answer from {endpoint} failed: {e}");
+            Some(if m.is_empty() {
+                format!("*I couldn't answer: {e}*")
+            } else {
+                format!("*The rest of the answer didn't come through: {e}*")
+            })
+        }
+    };
+
+    // a blank answer would only leave a gap in the conversation
+    if !m.is_empty() {
+        let history = API
+            .history(&HistoryBody {
+                message: m.clone(),
+                user: body.user.clone(),
+                scope: body.scope.clone(),
+            })
+            .await;
+
+        if let Err(e) = history {
+            warn!("Failed to add an answer to the history: {e}");
+        }
+    }
+
+    let content = match notice {
+        Some(notice) if m.is_empty() => format!("{header}{notice}"),
+        Some(notice) => format!("{header}{m}\n\n{notice}"),
+        None => format!("{header}{m}"),
+    };
+
+    reply
+        .edit(context, |r| {
+            r.content(content)
+                .components(|components| answer_buttons(components, false))
+        })
+        .await
+        .unwrap();
+
+    reply.suppress_embeds(context).await.unwrap();
+}
+
+/// The endpoint, header and request for a message command (right-click → Apps), if it's configured
+async fn context_menu_request(
+    context: &Context,
+    command: &ApplicationCommandInteraction,
+) -> Option<(String, String, ChatBody)> {
+    let config = config();
+    let menu = config.context_menus.as_ref()?.get(&command.data.name)?;
+    let Some((url, _)) = config
+        .endpoints
+        .iter()
+        .find(|(_, endpoint)| endpoint.id == menu.endpoint)
+    else {
+        warn!(
+            "Context menu `{}` uses unknown endpoint {}",
+            command.data.name, menu.endpoint
+        );
+        return None;
+    };
+    let target = command.data.resolved.messages.values().next()?;
+
+    let prior = match menu.history.unwrap_or_default().min(100) {
+        0 => reply_context(context, target).await,
+        limit => command
+            .channel_id
+            .messages(&context.http, |messages| {
+                messages.before(target.id).limit(limit as u64)
+            })
+            .await
+            .unwrap_or_default()
+            .iter()
+            .rev()
+            .map(|message| chat_message(context, message))
+            .collect(),
+    };
+
+    let input = remove_mentions(target.content_safe(context), context);
+    let header = format!(
+        "**{}** used *{}* on {}\n\n",
+        command.user.name,
+        command.data.name,
+        target.link()
+    );
+
+    Some((
+        url.clone(),
+        header,
+        ChatBody {
+            message: format!("{}: {input}", target.author.name),
+            user: command.user.name.clone(),
+            user_id: Some(command.user.id.to_string()),
+            attachments: Some(image_attachments(target)),
+            context: Some(prior),
+            scope: None,
+            args: None,
+            guild: command.guild_id.map(|id| id.to_string()),
+            regenerate: None,
+        },
+    ))
+}
+
+/// Answers /usage with a table of this guild's usage by day, user and endpoint
+async fn usage_report(context: &Context, command: &ApplicationCommandInteraction) {
+    let option = |name: &str| {
+        command
+            .data
+            .options
+            .iter()
+            .find(|option| option.name == name)
+            .and_then(|option| option.value.clone())
+    };
+    let query = UsageQuery {
+        days: option("days")
+            .and_then(|days| days.as_u64())
+            .map(|days| days as u32),
+        user_id: option("user").and_then(|id| id.as_str().map(String::from)),
+        guild: command.guild_id.map(|id| id.to_string()),
+    };
+
+    let content = match API.usage(&query).await {
+        Ok(rows) if rows.is_empty() => "Nothing's been used yet.".to_string(),
+        Ok(rows) => {
+            let total = rows.iter().map(|row| row.cost).sum::<f64>();
+            let mut table = String::new();
+
+            for row in &rows {
+                let line = format!(
+                    "{} {:<16} {:<24} {:>4} calls {:>8} tokens ${:.4}\n",
+                    row.day,
+                    row.user,
+                    row.endpoint,
+                    row.calls,
+                    row.prompt_tokens + row.completion_tokens,
+                    row.cost
+                );
+
+                // leave room for the total in Discord's 2000 characters
+                if table.len() + line.len() > USAGE_REPORT_LENGTH {
+                    table.push_str("…\n");
+                    break;
+                }
+                table.push_str(&line);
+            }
+
+            format!("```\n{table}```\nTotal: **${total:.4}**")
+        }
+        Err(e) => {
+            warn!("Failed to get usage: {e}");
+            "I couldn't get the usage right now, please try again later.".to_string()
+        }
+    };
+
+    command
+        .create_interaction_response(&context.http, |response| {
+            response
+                .kind(InteractionResponseType::ChannelMessageWithSource)
+                .interaction_response_data(|message| {
+                    message.content(content).flags(MessageFlags::EPHEMERAL)
+                })
+        })
+        .await
+        .unwrap();
+}
+
+/// Suggestions from an autocomplete task, cached for a minute
+async fn suggestions(task: &str, input: &str, user: &User, guild: Option<GuildId>) -> Vec<String> {
+    let key = (task.to_string(), input.trim().to_lowercase());
+
+    if let Some((at, choices)) = SUGGESTIONS.lock().unwrap().get(&key) {
+        if at.elapsed() < SUGGESTION_TTL {
+            return choices.clone();
+        }
+    }
+
+    // there's no time to try again before Discord gives up
+    let response = API
+        .clone()
+        .with_timeout(AUTOCOMPLETE_TIMEOUT)
+        .with_retries(0)
+        .autocomplete(&AutocompleteBody {
+            task: task.into(),
+            input: input.into(),
+            user: user.name.clone(),
+            user_id: Some(user.id.to_string()),
+            guild: guild.map(|id| id.to_string()),
+        })
+        .await;
+
+    let choices = match response {
+        Ok(choices) => choices,
+        Err(e) => {
+            warn!("Failed to get suggestions from {task}: {e}");
+            return vec![];
+        }
+    };
+
+    let mut cache = SUGGESTIONS.lock().unwrap();
+    cache.retain(|_, (at, _)| at.elapsed() < SUGGESTION_TTL);
+    if cache.len() < MAX_SUGGESTIONS {
+        cache.insert(key, (Instant::now(), choices.clone()));
+    }
+
+    choices
+}
+
+/// Answers autocomplete for the option being typed in, if it has an `autocomplete` task and the user
+/// may use the endpoint
+async fn autocomplete(context: &Context, autocomplete: AutocompleteInteraction) {
+    let config = config();
+    let Some(endpoint) = config
+        .endpoints
+        .values()
+        .find(|endpoint| endpoint.id.to_lowercase() == autocomplete.data.name)
+    else {
+        return;
+    };
+    let Some(focused) = autocomplete
+        .data
+        .options
+        .iter()
+        .find(|option| option.focused)
+    else {
+        return;
+    };
+
+    let task = endpoint
+        .options
+        .iter()
+        .flatten()
+        .find(|(name, _)| **name == focused.name)
+        .and_then(|(_, option)| match &option.kind {
+            ConfigOptionKind::String { autocomplete, .. } => autocomplete.clone(),
+            _ => None,
+        });
+    let Some(task) = task else {
+        return;
+    };
+
+    let roles = autocomplete
+        .member
+        .as_ref()
+        .map_or(&[][..], |member| &member.roles);
+    let requester = Requester::new(
+        context,
+        &autocomplete.user,
+        autocomplete.guild_id,
+        autocomplete.channel_id,
+        roles,
+    )
+    .await;
+    // denials are only audited when the command is run, not on every keystroke
+    if !access::allowed(&config, Some(endpoint), &requester) {
+        return;
+    }
+
+    let input = focused
+        .value
+        .as_ref()
+        .and_then(|value| value.as_str())
+        .unwrap_or_default();
+    let choices = suggestions(&task, input, &autocomplete.user, autocomplete.guild_id).await;
+
+    if let Err(e) = autocomplete
+        .create_autocomplete_response(&context.http, |response| {
+            // names and values can't be longer than 100 characters
+            for choice in &choices {
+                let choice = choice.chars().take(100).collect::<String>();
+                response.add_string_choice(&choice, &choice);
+            }
+            response
+        })
+        .await
+    {
+        warn!("Failed to send suggestions: {e}");
+    }
+}
+
+/// Handles the buttons under answers
+async fn press_button(context: &Context, component: MessageComponentInteraction) {
+    let exchange = EXCHANGES
+        .lock()
+        .unwrap()
+        .get(&component.message.id)
+        .cloned();
+
+    let Some(exchange) = exchange else {
+        component
+            .create_interaction_response(&context.http, |response| {
+                response
+                    .kind(InteractionResponseType::ChannelMessageWithSource)
+                    .interaction_response_data(|message| {
+                        message
+                            .content("I don't remember this answer anymore, try asking again!")
+                            .flags(MessageFlags::EPHEMERAL)
+                    })
+            })
+            .await
+            .unwrap();
+        return;
+    };
+
+    if component.user.id != exchange.asker {
+        component
+            .create_interaction_response(&context.http, |response| {
+                response
+                    .kind(InteractionResponseType::ChannelMessageWithSource)
+                    .interaction_response_data(|message| {
+                        message
+                            .content("Only the person who asked can use these buttons.")
+                            .flags(MessageFlags::EPHEMERAL)
+                    })
+            })
+            .await
+            .unwrap();
+        return;
+    }
+
+    let endpoint = exchange.endpoint.clone();
+    let mut body = exchange.body.clone();
+
+    match component.data.custom_id.as_str() {
+        "stop" | "regenerate" => {
+            if component.data.custom_id == "regenerate" {
+                let config = config();
+                let roles = component
+                    .member
+                    .as_ref()
+                    .map_or(&[][..], |member| &member.roles);
+                let requester = Requester::new(
+                    context,
+                    &component.user,
+                    component.guild_id,
+                    component.channel_id,
+                    roles,
+                )
+                .await;
+
+                if let Err(denial) =
+                    access::check(&config, config.endpoints.get(&endpoint), &requester)
+                {
+                    component
+                        .create_interaction_response(&context.http, |response| {
+                            response
+                                .kind(InteractionResponseType::ChannelMessageWithSource)
+                                .interaction_response_data(|message| {
+                                    message.content(denial).flags(MessageFlags::EPHEMERAL)
+                                })
+                        })
+                        .await
+                        .unwrap();
+                    return;
+                }
+            }
+
+            component
+                .create_interaction_response(&context.http, |response| {
+                    response.kind(InteractionResponseType::DeferredUpdateMessage)
+                })
+                .await
+                .unwrap();
+
+            if component.data.custom_id == "stop" {
+                exchange.stop.notify_one();
+                return;
+            }
+
+            info!("Regenerating an answer for {}", body.user);
+            // the new answer takes the old one's place, rather than asking again after it
+            body.regenerate = Some(true);
+
+            match API
+                .clone()
+                .with_timeout(ANSWER_TIMEOUT)
+                .chat_stream(&endpoint, &body)
+                .await
+            {
+                Ok(stream) => {
+                    let exchange = Exchange::new(&endpoint, body, exchange.header, exchange.asker);
+                    relay(context, component.message.clone(), stream, exchange).await;
+                }
+                Err(ClientError::RateLimited(limit)) => {
+                    component
+                        .create_followup_message(&context.http, |message| {
+                            message.content(limit).ephemeral(true)
+                        })
+                        .await
+                        .unwrap();
+                }
+                Err(e) => warn!("Failed to regenerate an answer: {e}"),
+            }
+        }
+        rating => {
+            let feedback = FeedbackBody {
+                user: component.user.name.clone(),
+                endpoint,
+                message: body.message,
+                answer: component
+                    .message
+                    .content
+                    .strip_prefix(&exchange.header)
+                    .unwrap_or(&component.message.content)
+                    .to_string(),
+                positive: rating == "feedback:up",
+            };
+
+            let reply = match API.feedback(&feedback).await {
+                Ok(_) => "Thanks for the feedback!",
+                Err(e) => {
+                    warn!("Failed to store feedback: {e}");
+                    "I couldn't save your feedback, please try again later."
+                }
+            };
+
+            component
+                .create_interaction_response(&context.http, |response| {
+                    response
+                        .kind(InteractionResponseType::ChannelMessageWithSource)
+                        .interaction_response_data(|message| {
+                            message.content(reply).flags(MessageFlags::EPHEMERAL)
+                        })
+                })
+                .await
+                .unwrap();
+        }
+    }
+}
+
 #[async_trait]
 impl EventHandler for Handler {
     async fn message(&self, context: Context, msg: Message) {
-        if msg.mentions_me(&context).await.unwrap() {
+        let config = config();
+        let direct = msg.guild_id.is_none();
+        if direct && (msg.author.bot || !dm_allowed(&context, &msg).await) {
+            return;
+        }
+
+        let mentioned = msg.mentions_me(&context).await.unwrap();
+        let mut thread = if msg.author.bot || direct {
+            None
+        } else {
+            conversation_thread(&context, &msg, mentioned).await
+        };
+
+        if direct || mentioned || thread.is_some() {
+            let roles = msg.member.as_ref().map_or(&[][..], |member| &member.roles);
+            let requester =
+                Requester::new(&context, &msg.author, msg.guild_id, msg.channel_id, roles).await;
+
+            if let Err(denial) = access::check(&config, None, &requester) {
+                msg.reply(&context, denial).await.unwrap();
+                return;
+            }
+
+            if thread.is_none() && threads_enabled(msg.guild_id, msg.channel_id) {
+                let question = remove_mentions(msg.content_safe(&context), &context);
+                thread = start_thread(&context, &msg, &question).await;
+            }
+
+            // threads keep a history of their own, shared by everyone in them, and DMs are kept
+            // apart from what the user says in guilds
+            let scope = match thread {
+                Some(id) => Some(format!("thread:{id}")),
+                None if direct => Some(format!("dm:{}", msg.author.id)),
+                None => None,
+            };
+            // no need to point at the question when it's the only conversation in the channel
+            let channel = thread.or(direct.then_some(msg.channel_id));
+            let prior = reply_context(&context, &msg).await;
             let mut retries = 0;
             'retry: loop {
                 if retries > 3 {
@@ -59,114 +939,102 @@ impl EventHandler for Handler {
                 let content = msg.content_safe(&context);
                 let content = remove_mentions(&content, &context);
 
-                let CategorizeResponse { category } = if retries == 3 {
-                    CategorizeResponse {
-                        category: CONFIG.fallback_endpoint.clone(),
-                    }
+                let attachments = image_attachments(&msg);
+
+                let category = if retries == 3 {
+                    config.fallback_endpoint.clone()
                 } else {
-                    info!(
-                        "GET http://{}/categorize user={}",
-                        var("API_URL").unwrap(),
-                        user
-                    );
-                    if let Ok(response) = reqwest::Client::new()
-                        .get(&format!("http://{}/categorize", var("API_URL").unwrap()))
-                        .timeout(Duration::from_secs(2))
-                        .json(&CategorizeBody {
+                    let response = API
+                        .clone()
+                        .with_timeout(CATEGORIZE_TIMEOUT)
+                        .categorize(&CategorizeBody {
                             message: content.clone(),
                             user: user.clone(),
+                            user_id: Some(msg.author.id.to_string()),
+                            attachments: Some(attachments.clone()),
+                            context: Some(prior.clone()),
+                            scope: scope.clone(),
+                            guild: msg.guild_id.map(|id| id.to_string()),
                         })
-                        .send()
-                        .await
-                    {
-                        response.json().await.unwrap_or(CategorizeResponse {
-                            category: CONFIG.fallback_endpoint.clone(),
-                        })
-                    } else {
-                        CategorizeResponse {
-                            category: CONFIG.fallback_endpoint.clone(),
+                        .await;
+
+                    match response {
+                        Ok(category) => category,
+                        Err(ClientError::RateLimited(limit)) => {
+                            waiting_reaction_handle.delete(&context).await.unwrap();
+                            msg.reply(&context, limit).await.unwrap();
+                            return;
                         }
+                        Err(_) => config.fallback_endpoint.clone(),
                     }
                 };
 
                 waiting_reaction_handle.delete(&context).await.unwrap();
 
-                let (ep_url, ep) = CONFIG
+                let (ep_url, ep) = config
                     .endpoints
                     .iter()
                     .filter(|(_, e)| e.id == category)
                     .next()
                     .unwrap();
 
+                if let Err(denial) = access::check(&config, Some(ep), &requester) {
+                    msg.reply(&context, denial).await.unwrap();
+                    return;
+                }
+
                 let category_reaction_handle = msg.react(&context, ep.icon).await.unwrap();
 
-                let typing_handle = context.http.start_typing(msg.channel_id.0).unwrap();
+                let typing_handle = context
+                    .http
+                    .start_typing(channel.unwrap_or(msg.channel_id).0)
+                    .unwrap();
 
                 let content = format!("{}: {content}", user);
 
-                let stream = if let Ok(response) = reqwest::Client::new()
-                    .get(
-                        &format!("http://{}{}", var("API_URL").unwrap(), ep_url)
-                            .tap(|s| info!("GET {s} user={}", user)),
-                    )
-                    .timeout(Duration::from_secs(20))
-                    .json(&ChatBody {
-                        message: content,
-                        user: user.clone(),
-                    })
-                    .send()
-                    .await
-                {
-                    response.json_nl_stream::<String>(1024)
-                } else {
-                    category_reaction_handle.delete(&context).await.unwrap();
-                    retries += 1;
-                    continue 'retry;
+                let body = ChatBody {
+                    message: content,
+                    user: user.clone(),
+                    user_id: Some(msg.author.id.to_string()),
+                    attachments: Some(attachments),
+                    context: Some(prior.clone()),
+                    scope: scope.clone(),
+                    args: None,
+                    guild: msg.guild_id.map(|id| id.to_string()),
+                    regenerate: None,
                 };
 
-                let user = msg.author.name.clone();
-                let mut reply_handle = msg.reply(&context, String::from("...")).await.unwrap();
-                let edit_index = EDIT_INDEX.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-
-                stream
-                    .for_each(|content| async {
-                        if let Ok(content) = content {
-                            let msg_contents = unsafe { EDIT_MAP.entry(edit_index).or_default() };
+                // this loop does the retrying, falling back to the fallback endpoint at the end
+                let response = API
+                    .clone()
+                    .with_timeout(ANSWER_TIMEOUT)
+                    .with_retries(0)
+                    .chat_stream(ep_url, &body)
+                    .await;
 
-                            {
-                                let mut msg_contents = msg_contents.lock().unwrap();
-                                msg_contents.push_str(&content);
-                            }
+                let stream = match response {
+                    Ok(stream) => stream,
+                    Err(ClientError::RateLimited(limit)) => {
+                        typing_handle.stop().unwrap();
+                        category_reaction_handle.delete(&context).await.unwrap();
+                        msg.reply(&context, limit).await.unwrap();
+                        return;
+                    }
+                    Err(e) => {
+                        warn!("Failed to get an answer from {ep_url}: {e}");
+                        category_reaction_handle.delete(&context).await.unwrap();
+                        retries += 1;
+                        continue 'retry;
+                    }
+                };
 
-                            reply_handle
-                                .clone()
-                                .edit(&context, |m| m.content(msg_contents.lock().unwrap()))
-                                .await
-                                .unwrap();
-                        }
-                    })
-                    .await;
-                {
-                    let msg_contents = unsafe { EDIT_MAP.remove(&edit_index).unwrap() };
-                    let m = Arc::try_unwrap(msg_contents).unwrap().into_inner().unwrap();
-
-                    info!(
-                        "POST http://{}/history user={}",
-                        var("API_URL").unwrap(),
-                        user
-                    );
-                    reqwest::Client::new()
-                        .post(format!("http://{}/history", var("API_URL").unwrap()))
-                        .json(&HistoryBody {
-                            message: m,
-                            user: user.clone(),
-                        })
-                        .send()
-                        .await
-                        .unwrap();
-                }
+                let reply_handle = match channel {
+                    Some(channel) => channel.say(&context, "...").await.unwrap(),
+                    None => msg.reply(&context, String::from("...")).await.unwrap(),
+                };
 
-                reply_handle.suppress_embeds(&context).await.unwrap();
+                let exchange = Exchange::new(ep_url, body, String::new(), msg.author.id);
+                relay(&context, reply_handle, stream, exchange).await;
 
                 typing_handle.stop().unwrap();
 
@@ -179,105 +1047,126 @@ impl EventHandler for Handler {
 
     async fn interaction_create(&self, context: Context, interaction: Interaction) {
         if let Interaction::ApplicationCommand(command) = interaction {
-            let endpoints = &Arc::clone(&CONFIG).endpoints;
+            let config = config();
+            let endpoints = &config.endpoints;
 
-            if let Some((url, _)) = endpoints
-                .iter()
-                .filter(|(_, endpoint)| endpoint.id.to_lowercase() == command.data.name)
-                .next()
-            {
-                // wtf
-                let input = command
-                    .data
-                    .options
+            let request = if command.data.kind == CommandType::Message {
+                context_menu_request(&context, &command)
+                    .await
+                    .map(Ok::<_, String>)
+            } else {
+                endpoints
                     .iter()
-                    .filter(|o| o.name == "message")
+                    .filter(|(_, endpoint)| endpoint.id.to_lowercase() == command.data.name)
                     .next()
-                    .unwrap()
-                    .value
-                    .as_ref()
-                    .unwrap()
-                    .as_str()
-                    .unwrap()
-                    .to_string();
-                let response = reqwest::Client::new()
-                    .get(format!("http://{}{url}", var("API_URL").unwrap()))
-                    .json(&ChatBody {
-                        message: input.clone(),
-                        user: command.member.clone().unwrap().user.name,
-                    })
-                    .send()
-                    .await
-                    .unwrap();
+                    .map(|(url, endpoint)| {
+                        let input = CommandInput::parse(endpoint, &command.data)?;
+                        let user = command.user.name.clone();
 
-                let stream = response.json_nl_stream::<String>(1024);
-                let edit_index = EDIT_INDEX.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                        Ok((
+                            url.clone(),
+                            format!("**{}**: *{}*\n\n", user, input.summary()),
+                            ChatBody {
+                                message: input.message,
+                                user,
+                                user_id: Some(command.user.id.to_string()),
+                                attachments: Some(input.attachments),
+                                context: None,
+                                scope: None,
+                                args: Some(input.args),
+                                guild: command.guild_id.map(|id| id.to_string()),
+                                regenerate: None,
+                            },
+                        ))
+                    })
+            };
 
-                let header = format!(
-                    "**{}**: *{}*\n\n",
-                    command.member.clone().unwrap().user.name,
-                    input
-                );
+            // access is checked once the endpoint is known, so the rules can be per endpoint
+            let roles = command
+                .member
+                .as_ref()
+                .map_or(&[][..], |member| &member.roles);
+            let requester = Requester::new(
+                &context,
+                &command.user,
+                command.guild_id,
+                command.channel_id,
+                roles,
+            )
+            .await;
+            let request = request.map(|request| {
+                request.and_then(|(url, header, body)| {
+                    access::check(&config, config.endpoints.get(&url), &requester)?;
+                    Ok((url, header, body))
+                })
+            });
 
+            if let Some(Err(error)) = &request {
                 command
                     .create_interaction_response(&context.http, |response| {
                         response
                             .kind(InteractionResponseType::ChannelMessageWithSource)
                             .interaction_response_data(|message| {
-                                message
-                                    .content(header.clone())
-                                    .flags(MessageFlags::SUPPRESS_EMBEDS)
+                                message.content(error).flags(MessageFlags::EPHEMERAL)
                             })
                     })
                     .await
                     .unwrap();
+                return;
+            }
 
-                {
-                    let mut msg_contents = unsafe { EDIT_MAP.entry(edit_index).or_default() }
-                        .lock()
-                        .unwrap();
-                    *msg_contents = header;
-                }
+            if let Some(Ok((url, header, body))) = request {
+                // Discord gives up on interactions that aren't acknowledged within 3 seconds
+                command
+                    .create_interaction_response(&context.http, |response| {
+                        response.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+                    })
+                    .await
+                    .unwrap();
 
-                stream
-                    .for_each(|content| async {
-                        if let Ok(content) = content {
-                            let msg_contents = unsafe { EDIT_MAP.entry(edit_index).or_default() };
+                let response = API
+                    .clone()
+                    .with_timeout(ANSWER_TIMEOUT)
+                    .chat_stream(&url, &body)
+                    .await;
 
-                            {
-                                let mut msg_contents = msg_contents.lock().unwrap();
-                                msg_contents.push_str(&content);
+                let stream = match response {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        let content = match e {
+                            ClientError::RateLimited(limit) => limit,
+                            e => {
+                                warn!("Failed to get an answer from {url}: {e}");
+                                UNAVAILABLE.into()
                             }
+                        };
 
-                            command
-                                .edit_original_interaction_response(&context.http, |response| {
-                                    response.content(msg_contents.lock().unwrap())
-                                })
-                                .await
-                                .unwrap();
-                        }
-                    })
-                    .await;
+                        // only the asker needs to see it, which a deferred response can't be
+                        // switched to
+                        command
+                            .delete_original_interaction_response(&context.http)
+                            .await
+                            .unwrap();
+                        command
+                            .create_followup_message(&context.http, |message| {
+                                message.content(content).ephemeral(true)
+                            })
+                            .await
+                            .unwrap();
+                        return;
+                    }
+                };
 
-                {
-                    let msg_contents = unsafe { EDIT_MAP.remove(&edit_index).unwrap() };
-                    let m = Arc::try_unwrap(msg_contents).unwrap().into_inner().unwrap();
-
-                    info!(
-                        "POST http://{}/history user={}",
-                        var("API_URL").unwrap(),
-                        command.member.clone().unwrap().user.name
-                    );
-                    reqwest::Client::new()
-                        .post(format!("http://{}/history", var("API_URL").unwrap()))
-                        .json(&HistoryBody {
-                            message: m,
-                            user: command.member.clone().unwrap().user.name,
-                        })
-                        .send()
-                        .await
-                        .unwrap();
-                }
+                // the answer is edited into the response like any other message, so it gets the
+                // same buttons
+                let reply = command
+                    .edit_original_interaction_response(&context.http, |response| {
+                        response.content(format!("{header}..."))
+                    })
+                    .await
+                    .unwrap();
+                let exchange = Exchange::new(&url, body, header, command.user.id);
+                relay(&context, reply, stream, exchange).await;
             } else if command.data.name == "help" {
                 command
                     .create_interaction_response(&context.http, |response| {
@@ -296,44 +1185,31 @@ impl EventHandler for Handler {
                                             embed = embed.field(format!("/{}", endpoint.id.to_lowercase()), &endpoint.designation, false);
                                         }
 
+                                        if let Some(menus) = &config.context_menus {
+                                            embed = embed.field("**Apps**", format!("Right-click a message, then *Apps* → {}", menus.keys().map(|name| format!("*{name}*")).collect::<Vec<_>>().join(", ")), false);
+                                        }
+
                                         embed
                                     })
                             })
                     })
                     .await
                     .unwrap();
+            } else if command.data.name == "usage" {
+                usage_report(&context, &command).await;
             }
+        } else if let Interaction::MessageComponent(component) = interaction {
+            press_button(&context, component).await;
+        } else if let Interaction::Autocomplete(interaction) = interaction {
+            autocomplete(&context, interaction).await;
         }
     }
 
     async fn ready(&self, context: Context, ready: Ready) {
-        let endpoints = CONFIG.endpoints.clone();
-
-        for (_, endpoint) in &endpoints {
-            Command::create_global_application_command(&context.http, |command| {
-                command
-                    .name(endpoint.id.to_lowercase())
-                    .description(endpoint.designation.clone())
-                    .create_option(|option| {
-                        option
-                            .name("message")
-                            .description("The message to relay")
-                            .kind(CommandOptionType::String)
-                            .required(true)
-                    })
-            })
-            .await
-            .unwrap();
+        if let Err(e) = commands::sync_commands(&context.http, &config(), None).await {
+            error!("Failed to sync commands: {e}");
         }
 
-        Command::create_global_application_command(&context.http, |command| {
-            command
-                .name("help")
-                .description("Show a full list of features")
-        })
-        .await
-        .unwrap();
-
         context
             .set_activity(Activity::watching("for mentions"))
             .await;
@@ -347,6 +1223,7 @@ async fn main() -> Result<()> {
 
     let token = var("DISCORD_TOKEN")?;
     let intents = GatewayIntents::GUILD_MESSAGES
+        | GatewayIntents::DIRECT_MESSAGES
         | GatewayIntents::MESSAGE_CONTENT
         | GatewayIntents::GUILD_MESSAGE_REACTIONS
         | GatewayIntents::GUILD_MESSAGE_TYPING;
@@ -360,6 +1237,8 @@ async fn main() -> Result<()> {
         exit(0);
     });
 
+    spawn(watch_config(client.cache_and_http.http.clone()));
+
     if let Err(e) = client.start().await {
         error!("Client error: {e}");
     }